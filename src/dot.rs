@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use graphviz_rust::dot_structures::{
+    Attribute, Edge, EdgeTy, Graph, GraphAttributes, Id, Node, NodeId, Stmt, Vertex,
+};
+use graphviz_rust::printer::{DotPrinter, PrinterContext};
+
+use crate::{Op, Value};
+
+/// How `Value::to_graph` renders the nodes.
+#[derive(Clone, Debug)]
+pub struct DotOptions {
+    /// Digits after the decimal point for values and gradients.
+    pub precision: usize,
+}
+
+impl Default for DotOptions {
+    fn default() -> Self {
+        DotOptions { precision: 4 }
+    }
+}
+
+impl Value {
+    /// The graph of `self` as a Graphviz digraph, laid out like micrograd:
+    /// every node appears once as a record `label | data | grad`, and the op
+    /// that computed it is a separate ellipse pointing to it, with an edge
+    /// from each of its inputs.
+    ///
+    /// The DOT nodes are named by position (`v0`, `op3`, ...), children
+    /// first, so the same graph always gives the same output.
+    pub fn to_graph(&self, options: &DotOptions) -> Graph {
+        let mut order = self.topological_sort();
+        order.reverse();
+        let positions: HashMap<usize, usize> = order.iter().enumerate().map(|(i, node)| (node.key(), i)).collect();
+
+        let mut stmts: Vec<Stmt> = vec![
+            Stmt::Attribute(attribute("rankdir", "LR")),
+            Stmt::GAttribute(GraphAttributes::Node(vec![
+                attribute("shape", "record"),
+                attribute("colorscheme", "set28"),
+            ])),
+        ];
+        for (position, node) in order.iter().enumerate() {
+            let value_id = format!("v{}", position);
+            let name = match (node.label(), node.op()) {
+                (Some(label), _) => label,
+                (None, Op::None) => format!("v{}", node.id()),
+                (None, Op::Const) => "const".to_owned(),
+                (None, _) => String::new(),
+            };
+            let record = format!(
+                "{{{} | data {:.p$} | grad {:.p$}}}",
+                escape(&name),
+                node.value(),
+                node.gradient(),
+                p = options.precision
+            );
+            stmts.push(Stmt::Node(Node::new(node_id(&value_id), vec![quoted("label", &record)])));
+
+            let Some((symbol, color)) = op_style(&node.op()) else {
+                continue;
+            };
+            let op_id = format!("op{}", position);
+            stmts.push(Stmt::Node(Node::new(
+                node_id(&op_id),
+                vec![
+                    quoted("label", &escape(&symbol)),
+                    attribute("shape", "ellipse"),
+                    attribute("color", &color.to_string()),
+                ],
+            )));
+            stmts.push(edge(&op_id, &value_id));
+            for child in node.children().iter() {
+                stmts.push(edge(&format!("v{}", positions[&child.key()]), &op_id));
+            }
+        }
+
+        Graph::DiGraph {
+            id: Id::Anonymous(String::new()),
+            strict: false,
+            stmts,
+        }
+    }
+
+    /// `to_graph` with the default options, printed in the DOT language.
+    pub fn export_graph(&self) -> String {
+        self.export_graph_with(&DotOptions::default())
+    }
+
+    pub fn export_graph_with(&self, options: &DotOptions) -> String {
+        self.to_graph(options).print(&mut PrinterContext::default())
+    }
+
+    /// Writes `export_graph` to `path`, e.g. to run `dot -Tsvg` on it.
+    pub fn write_dot(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        std::fs::write(path, self.export_graph())
+    }
+}
+
+/// Text and color (index into the set28 colorscheme) of the op node, `None`
+/// for the leaves.
+fn op_style(op: &Op) -> Option<(String, u16)> {
+    let style = match op {
+        Op::None | Op::Const => return None,
+        Op::Add => ("+".to_owned(), 1),
+        Op::Sub => ("-".to_owned(), 1),
+        Op::Mul => ("*".to_owned(), 2),
+        Op::Div => ("/".to_owned(), 2),
+        Op::Neg => ("neg".to_owned(), 2),
+        Op::Tanh => ("tanh".to_owned(), 3),
+        Op::Exp => ("exp".to_owned(), 4),
+        Op::Pow(exponent) => (format!("pow {}", exponent), 5),
+        Op::Relu => ("relu".to_owned(), 6),
+        Op::Log => ("log".to_owned(), 4),
+        Op::Sqrt => ("sqrt".to_owned(), 5),
+        Op::Sin => ("sin".to_owned(), 8),
+        Op::Cos => ("cos".to_owned(), 8),
+        Op::Sigmoid => ("sigmoid".to_owned(), 3),
+        Op::Softplus => ("softplus".to_owned(), 6),
+        Op::Abs => ("abs".to_owned(), 6),
+        Op::LeakyRelu(alpha) => (format!("leaky_relu {}", alpha), 6),
+        Op::Gelu => ("gelu".to_owned(), 6),
+        Op::PowValue => ("pow".to_owned(), 5),
+        Op::Max => ("max".to_owned(), 6),
+        Op::Min => ("min".to_owned(), 6),
+        Op::Atan2 => ("atan2".to_owned(), 8),
+        Op::Custom(function) => (function.name(), function.color()),
+    };
+    Some(style)
+}
+
+// the characters with a meaning in record labels, and the quotes
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '"' | '\\' | '{' | '}' | '|' | '<' | '>') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn node_id(id: &str) -> NodeId {
+    NodeId(Id::Plain(id.to_owned()), None)
+}
+
+fn attribute(key: &str, value: &str) -> Attribute {
+    Attribute(Id::Plain(key.to_owned()), Id::Plain(value.to_owned()))
+}
+
+fn quoted(key: &str, value: &str) -> Attribute {
+    Attribute(Id::Plain(key.to_owned()), Id::Escaped(format!("\"{}\"", value)))
+}
+
+fn edge(from: &str, to: &str) -> Stmt {
+    Stmt::Edge(Edge {
+        ty: EdgeTy::Pair(Vertex::N(node_id(from)), Vertex::N(node_id(to))),
+        attributes: vec![],
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::leaf;
+
+    #[test]
+    fn shared_nodes_are_emitted_once() {
+        let x = leaf(2.0, "x");
+        let y = leaf(3.0, "y");
+        let s = leaf(5.0, "v1") + leaf(1.0, "v2");
+        let out = (s.clone() * x) * (s.clone() * y);
+        out.backward();
+
+        let dot = out.export_graph();
+        let mut order = out.topological_sort();
+        order.reverse();
+        let sum = order.iter().position(|node| node.ptr_eq(&s)).unwrap();
+        let root = order.len() - 1;
+
+        assert!(dot.starts_with("digraph"));
+        assert_eq!(dot.matches(&format!("v{}[", sum)).count(), 1);
+        assert_eq!(dot.matches(&format!("op{}[", sum)).count(), 1);
+        // the sum feeds two products
+        assert_eq!(dot.matches(&format!("v{} -> op", sum)).count(), 2);
+        assert!(dot.contains(&format!("op{} -> v{}", root, root)));
+        assert!(dot.contains("{x | data 2.0000 | grad 108.0000}"));
+
+        // 8 value nodes, 4 op nodes
+        assert_eq!(dot.matches("label=").count(), 12);
+        assert_eq!(dot.matches("->").count(), 4 + 2 * 4);
+    }
+
+    #[test]
+    fn reproducible_output() {
+        let build = || {
+            let _ids = crate::IdContext::new().enter();
+            let a = Value::new(0.5);
+            let b = Value::new(-1.5);
+            let c = (a.clone() * b.clone()).tanh() + a;
+            c.backward();
+            c
+        };
+        let (first, second) = (build(), build());
+        assert_eq!(first.export_graph(), second.export_graph());
+        assert_eq!(first.to_json(), second.to_json());
+        assert!(first.export_graph().contains("{v0 | data 0.5000"));
+    }
+
+    #[test]
+    fn precision_and_escaping() {
+        let a = leaf(1.0 / 3.0, "{a|b}");
+        let out = a.clone().pow(2.0);
+        let dot = out.export_graph_with(&DotOptions { precision: 1 });
+        assert!(dot.contains(r#"label="{\{a\|b\} | data 0.3 | grad 0.0}""#), "{}", dot);
+        assert!(dot.contains(r#"label="pow 2""#));
+        assert!(dot.contains("color=5"));
+    }
+
+    #[test]
+    fn write_dot() {
+        let out = leaf(2.0, "x").tanh();
+        let path = std::env::temp_dir().join(format!("babygrad-write_dot-{}.dot", std::process::id()));
+        out.write_dot(&path).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written, out.export_graph());
+    }
+}
@@ -0,0 +1,400 @@
+use std::ops;
+use std::fmt;
+
+use crate::Function;
+use crate::engine::{gelu, gelu_derivative, leaky_relu, pow_exponent_gradient, sigmoid, sign, softplus};
+
+/// A dual number `value + tangent * ε` (with ε² = 0) for forward-mode
+/// automatic differentiation: every operation carries the directional
+/// derivative along with the value, no graph is recorded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Dual {
+    value: f64,
+    tangent: f64,
+}
+
+impl fmt::Display for Dual {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Dual[{}, tangent={}]", self.value, self.tangent)
+    }
+}
+
+impl Dual {
+    pub fn new(value: f64, tangent: f64) -> Dual {
+        Dual { value, tangent }
+    }
+
+    /// A constant, its tangent is zero.
+    pub fn constant(value: f64) -> Dual {
+        Dual::new(value, 0.0)
+    }
+
+    /// The variable we differentiate with respect to, its tangent is one.
+    pub fn variable(value: f64) -> Dual {
+        Dual::new(value, 1.0)
+    }
+
+    pub fn vec(values: &[f64], tangents: &[f64]) -> Vec<Dual> {
+        values
+            .iter()
+            .zip(tangents.iter())
+            .map(|(value, tangent)| Dual::new(*value, *tangent))
+            .collect::<Vec<Dual>>()
+    }
+
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    pub fn tangent(&self) -> f64 {
+        self.tangent
+    }
+
+    /// Applies the chain rule for a unary function `f` with derivative `df`.
+    fn unary_op(self, f: impl FnOnce(f64) -> f64, df: impl FnOnce(f64) -> f64) -> Dual {
+        Dual::new(f(self.value), df(self.value) * self.tangent)
+    }
+
+    pub fn tanh(self) -> Dual {
+        let t = self.value.tanh();
+        self.unary_op(|_| t, |_| 1.0 - t * t)
+    }
+
+    pub fn exp(self) -> Dual {
+        let e = self.value.exp();
+        self.unary_op(|_| e, |_| e)
+    }
+
+    pub fn pow(self, exponent: f64) -> Dual {
+        self.unary_op(|x| x.powf(exponent), |x| exponent * x.powf(exponent - 1.0))
+    }
+
+    pub fn relu(self) -> Dual {
+        self.unary_op(|x| x.max(0.0), |x| if x > 0.0 { 1.0 } else { 0.0 })
+    }
+
+    pub fn log(self) -> Dual {
+        self.unary_op(f64::ln, |x| 1.0 / x)
+    }
+
+    pub fn sqrt(self) -> Dual {
+        self.unary_op(f64::sqrt, |x| 0.5 / x.sqrt())
+    }
+
+    pub fn sin(self) -> Dual {
+        self.unary_op(f64::sin, f64::cos)
+    }
+
+    pub fn cos(self) -> Dual {
+        self.unary_op(f64::cos, |x| -x.sin())
+    }
+
+    pub fn sigmoid(self) -> Dual {
+        let s = sigmoid(self.value);
+        self.unary_op(|_| s, |_| s * (1.0 - s))
+    }
+
+    pub fn softplus(self) -> Dual {
+        self.unary_op(softplus, sigmoid)
+    }
+
+    pub fn abs(self) -> Dual {
+        self.unary_op(f64::abs, sign)
+    }
+
+    pub fn leaky_relu(self, alpha: f64) -> Dual {
+        self.unary_op(|x| leaky_relu(x, alpha), |x| if x > 0.0 { 1.0 } else { alpha })
+    }
+
+    pub fn gelu(self) -> Dual {
+        self.unary_op(gelu, gelu_derivative)
+    }
+
+    pub fn pow_value(self, exponent: Dual) -> Dual {
+        let (a, b) = (self.value, exponent.value);
+        let value = a.powf(b);
+        let tangent = b * a.powf(b - 1.0) * self.tangent + pow_exponent_gradient(a, value) * exponent.tangent;
+        Dual::new(value, tangent)
+    }
+
+    /// Follows the selected operand, `self` on a tie.
+    pub fn max(self, rhs: Dual) -> Dual {
+        if self.value >= rhs.value { self } else { rhs }
+    }
+
+    pub fn min(self, rhs: Dual) -> Dual {
+        if self.value <= rhs.value { self } else { rhs }
+    }
+
+    pub fn atan2(self, x: Dual) -> Dual {
+        let r2 = x.value * x.value + self.value * self.value;
+        Dual::new(
+            self.value.atan2(x.value),
+            (x.value * self.tangent - self.value * x.tangent) / r2,
+        )
+    }
+
+    /// Applies a user-defined `Function`, using its local gradients as the
+    /// partial derivatives.
+    pub fn apply(function: &dyn Function, inputs: &[Dual]) -> Dual {
+        let values: Vec<f64> = inputs.iter().map(Dual::value).collect();
+        let output = function.forward(&values);
+        let tangent = function
+            .backward(&values, output)
+            .iter()
+            .zip(inputs.iter())
+            .map(|(local, input)| local * input.tangent)
+            .sum();
+        Dual::new(output, tangent)
+    }
+}
+
+/// Jacobian-vector product of `f` at `primals` along `tangents`.
+/// Returns the outputs of `f` and `J · tangents`, in a single forward pass.
+pub fn jvp<F>(f: F, primals: &[f64], tangents: &[f64]) -> (Vec<f64>, Vec<f64>)
+where
+    F: Fn(&[Dual]) -> Vec<Dual>,
+{
+    assert!(primals.len() == tangents.len());
+    let outputs = f(&Dual::vec(primals, tangents));
+    (
+        outputs.iter().map(Dual::value).collect(),
+        outputs.iter().map(Dual::tangent).collect(),
+    )
+}
+
+
+impl ops::Add<Dual> for Dual {
+    type Output = Dual;
+
+    fn add(self, rhs: Dual) -> Dual {
+        Dual::new(self.value + rhs.value, self.tangent + rhs.tangent)
+    }
+}
+
+impl ops::Sub<Dual> for Dual {
+    type Output = Dual;
+
+    fn sub(self, rhs: Dual) -> Dual {
+        Dual::new(self.value - rhs.value, self.tangent - rhs.tangent)
+    }
+}
+
+impl ops::Mul<Dual> for Dual {
+    type Output = Dual;
+
+    fn mul(self, rhs: Dual) -> Dual {
+        Dual::new(
+            self.value * rhs.value,
+            self.tangent * rhs.value + self.value * rhs.tangent,
+        )
+    }
+}
+
+impl ops::Div<Dual> for Dual {
+    type Output = Dual;
+
+    fn div(self, rhs: Dual) -> Dual {
+        Dual::new(
+            self.value / rhs.value,
+            (self.tangent * rhs.value - self.value * rhs.tangent) / (rhs.value * rhs.value),
+        )
+    }
+}
+
+impl ops::Neg for Dual {
+    type Output = Dual;
+
+    fn neg(self) -> Dual {
+        Dual::new(-self.value, -self.tangent)
+    }
+}
+
+impl ops::Add<f64> for Dual {
+    type Output = Dual;
+
+    fn add(self, rhs: f64) -> Dual {
+        self + Dual::constant(rhs)
+    }
+}
+
+impl ops::Sub<f64> for Dual {
+    type Output = Dual;
+
+    fn sub(self, rhs: f64) -> Dual {
+        self - Dual::constant(rhs)
+    }
+}
+
+impl ops::Mul<f64> for Dual {
+    type Output = Dual;
+
+    fn mul(self, rhs: f64) -> Dual {
+        self * Dual::constant(rhs)
+    }
+}
+
+impl ops::Div<f64> for Dual {
+    type Output = Dual;
+
+    fn div(self, rhs: f64) -> Dual {
+        self / Dual::constant(rhs)
+    }
+}
+
+impl ops::Add<Dual> for f64 {
+    type Output = Dual;
+
+    fn add(self, rhs: Dual) -> Dual {
+        Dual::constant(self) + rhs
+    }
+}
+
+impl ops::Sub<Dual> for f64 {
+    type Output = Dual;
+
+    fn sub(self, rhs: Dual) -> Dual {
+        Dual::constant(self) - rhs
+    }
+}
+
+impl ops::Mul<Dual> for f64 {
+    type Output = Dual;
+
+    fn mul(self, rhs: Dual) -> Dual {
+        Dual::constant(self) * rhs
+    }
+}
+
+impl ops::Div<Dual> for f64 {
+    type Output = Dual;
+
+    fn div(self, rhs: Dual) -> Dual {
+        Dual::constant(self) / rhs
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+
+    #[test]
+    fn unary_functions() {
+        let x = Dual::variable(0.5);
+        assert_approx!(x.tanh().tangent(), 1.0 - 0.5_f64.tanh().powi(2));
+        assert_approx!(x.exp().tangent(), 0.5_f64.exp());
+        assert_approx!(x.pow(3.0).tangent(), 0.75);
+        assert_approx!(x.relu().tangent(), 1.0);
+        assert_approx!((-x).relu().tangent(), 0.0);
+    }
+
+    #[test]
+    fn unary_functions_match_reverse_mode() {
+        let duals: Vec<fn(Dual) -> Dual> = vec![
+            Dual::log, Dual::sqrt, Dual::sin, Dual::cos, Dual::sigmoid,
+            Dual::softplus, Dual::abs, |x| x.leaky_relu(0.1), Dual::gelu,
+        ];
+        let values: Vec<fn(Value) -> Value> = vec![
+            Value::log, Value::sqrt, Value::sin, Value::cos, Value::sigmoid,
+            Value::softplus, Value::abs, |x| x.leaky_relu(0.1), Value::gelu,
+        ];
+        for (dual, value) in duals.iter().zip(values.iter()) {
+            for x in [0.4, 2.3] {
+                let a = Value::new(x);
+                let b = value(a.clone());
+                b.backward();
+                let d = dual(Dual::variable(x));
+                assert_approx!(d.value(), b.value());
+                assert_approx!(d.tangent(), a.gradient());
+            }
+        }
+    }
+
+    #[test]
+    fn binary_functions_match_reverse_mode() {
+        let duals: Vec<fn(Dual, Dual) -> Dual> = vec![Dual::pow_value, Dual::max, Dual::min, Dual::atan2];
+        let values: Vec<fn(Value, Value) -> Value> = vec![Value::pow_value, Value::max, Value::min, Value::atan2];
+        for (dual, value) in duals.iter().zip(values.iter()) {
+            for (x, y) in [(1.7, 0.4), (0.3, -2.1)] {
+                let a = Value::new(x);
+                let b = Value::new(y);
+                let c = value(a.clone(), b.clone());
+                c.backward();
+                let d = dual(Dual::new(x, 0.3), Dual::new(y, -0.8));
+                assert_approx!(d.value(), c.value());
+                assert_approx!(d.tangent(), 0.3 * a.gradient() - 0.8 * b.gradient());
+            }
+        }
+    }
+
+    #[test]
+    fn arithmetic_operations() {
+        let x = Dual::variable(2.0);
+        assert_approx!((x + 3.0).tangent(), 1.0);
+        assert_approx!((3.0 - x).tangent(), -1.0);
+        assert_approx!((3.0 * x * x).tangent(), 12.0);
+        assert_approx!((1.0 / x).tangent(), -0.25);
+        assert_approx!((x / 4.0).value(), 0.5);
+    }
+
+    // f(x, y) = [tanh(x * y) + exp(x) / y, relu(x - y)^2 - 2 * x]
+    fn f_dual(x: &[Dual]) -> Vec<Dual> {
+        vec![
+            (x[0] * x[1]).tanh() + x[0].exp() / x[1],
+            (x[0] - x[1]).relu().pow(2.0) - 2.0 * x[0],
+        ]
+    }
+
+    fn f_value(x: &[Value]) -> Vec<Value> {
+        vec![
+            (x[0].clone() * x[1].clone()).tanh() + x[0].clone().exp() / x[1].clone(),
+            (x[0].clone() - x[1].clone()).relu().pow(2.0) - 2.0 * x[0].clone(),
+        ]
+    }
+
+    #[test]
+    fn jvp_matches_reverse_mode() {
+        let primals = [1.5, 0.4];
+        let tangents = [0.3, -2.0];
+        let (outputs, jv) = jvp(f_dual, &primals, &tangents);
+
+        let inputs = Value::vec(&primals);
+        for (i, output) in f_value(&inputs).iter().enumerate() {
+            output.backward();
+            let expected: f64 = inputs
+                .iter()
+                .zip(tangents.iter())
+                .map(|(input, tangent)| input.gradient() * tangent)
+                .sum();
+            assert_approx!(outputs[i], output.value());
+            assert_approx!(jv[i], expected);
+        }
+    }
+
+    #[derive(Debug)]
+    struct Product;
+
+    impl Function for Product {
+        fn forward(&self, inputs: &[f64]) -> f64 {
+            inputs.iter().product()
+        }
+
+        fn backward(&self, inputs: &[f64], output: f64) -> Vec<f64> {
+            inputs.iter().map(|x| output / x).collect()
+        }
+
+        fn name(&self) -> String {
+            "product".to_owned()
+        }
+    }
+
+    #[test]
+    fn custom_function() {
+        let inputs = Dual::vec(&[2.0, 3.0, 4.0], &[1.0, 0.0, 1.0]);
+        let out = Dual::apply(&Product, &inputs);
+        assert_approx!(out.value(), 24.0);
+        assert_approx!(out.tangent(), 12.0 + 6.0);
+    }
+}
@@ -1,33 +1,243 @@
 use std::ops;
-use std::{cell::{Ref, RefCell, RefMut},
-     rc::Rc,
-     //collections::{HashMap, HashSet},
-     sync::atomic::AtomicUsize};
+use std::{cell::{Cell, RefCell},
+     collections::{HashMap, HashSet},
+     sync::{Arc, Mutex, MutexGuard},
+     sync::atomic::{AtomicUsize, Ordering}};
+use std::error::Error;
 use std::fmt;
+use std::f64::consts::{FRAC_1_SQRT_2, FRAC_2_SQRT_PI};
+
+use crate::BabygradError;
 
-/*
-extern crate graphviz_rust;
-use graphviz_rust::dot_structures::*;
-use graphviz_rust::parse;
-use graphviz_rust::dot_generator::*;
-use graphviz_rust::attributes::GraphAttributes as GAttributes;
-use self::graphviz_rust::attributes::{EdgeAttributes, NodeAttributes, rankdir, shape};
-use self::graphviz_rust::printer::{DotPrinter, PrinterContext};
-*/
 
 #[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub enum Op {
     None,
+    Const,
     Add,
     Mul,
     Tanh,
     Exp,
-    Pow,
+    Pow(f64),
     Sub,
     Div,
     Relu,
-    Neg
+    Neg,
+    Log,
+    Sqrt,
+    Sin,
+    Cos,
+    Sigmoid,
+    Softplus,
+    Abs,
+    LeakyRelu(f64),
+    Gelu,
+    PowValue,
+    Max,
+    Min,
+    Atan2,
+    Custom(Arc<dyn Function>)
+}
+
+impl Op {
+    /// Short lowercase name of the op, e.g. `"leaky_relu"`, without its parameter.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::None => "none",
+            Op::Const => "const",
+            Op::Add => "add",
+            Op::Mul => "mul",
+            Op::Sub => "sub",
+            Op::Div => "div",
+            Op::Neg => "neg",
+            Op::Tanh => "tanh",
+            Op::Exp => "exp",
+            Op::Pow(_) => "pow",
+            Op::Relu => "relu",
+            Op::Log => "log",
+            Op::Sqrt => "sqrt",
+            Op::Sin => "sin",
+            Op::Cos => "cos",
+            Op::Sigmoid => "sigmoid",
+            Op::Softplus => "softplus",
+            Op::Abs => "abs",
+            Op::LeakyRelu(_) => "leaky_relu",
+            Op::Gelu => "gelu",
+            Op::PowValue => "pow_value",
+            Op::Max => "max",
+            Op::Min => "min",
+            Op::Atan2 => "atan2",
+            Op::Custom(_) => "custom",
+        }
+    }
+
+    /// Number of inputs of a node computed by the op, `None` when any number
+    /// is allowed.
+    pub fn arity(&self) -> Option<usize> {
+        match self {
+            Op::None | Op::Const => Some(0),
+            Op::Add | Op::Mul | Op::Sub | Op::Div | Op::PowValue | Op::Max | Op::Min | Op::Atan2 => Some(2),
+            Op::Custom(_) => None,
+            _ => Some(1),
+        }
+    }
+
+    /// Computes the output of the op from its input values.
+    pub(crate) fn forward(&self, inputs: &[f64]) -> f64 {
+        match self {
+            Op::None | Op::Const => unreachable!("leaves have no inputs"),
+            Op::Add => inputs[0] + inputs[1],
+            Op::Sub => inputs[0] - inputs[1],
+            Op::Mul => inputs[0] * inputs[1],
+            Op::Div => inputs[0] / inputs[1],
+            Op::Neg => -inputs[0],
+            Op::Tanh => inputs[0].tanh(),
+            Op::Exp => inputs[0].exp(),
+            Op::Pow(exponent) => inputs[0].powf(*exponent),
+            Op::Relu => inputs[0].max(0.0),
+            Op::Log => inputs[0].ln(),
+            Op::Sqrt => inputs[0].sqrt(),
+            Op::Sin => inputs[0].sin(),
+            Op::Cos => inputs[0].cos(),
+            Op::Sigmoid => sigmoid(inputs[0]),
+            Op::Softplus => softplus(inputs[0]),
+            Op::Abs => inputs[0].abs(),
+            Op::LeakyRelu(alpha) => leaky_relu(inputs[0], *alpha),
+            Op::Gelu => gelu(inputs[0]),
+            Op::PowValue => inputs[0].powf(inputs[1]),
+            Op::Max => inputs[0].max(inputs[1]),
+            Op::Min => inputs[0].min(inputs[1]),
+            Op::Atan2 => inputs[0].atan2(inputs[1]),
+            Op::Custom(function) => function.forward(inputs),
+        }
+    }
+
+    /// Writes d(output)/d(input) for every input into `locals`, the numeric
+    /// backward rule shared by all the engines.
+    pub(crate) fn local_gradients(&self, inputs: &[f64], output: f64, locals: &mut [f64]) {
+        match self {
+            Op::None | Op::Const => (),
+            Op::Add => {
+                locals[0] = 1.0;
+                locals[1] = 1.0;
+            }
+            Op::Sub => {
+                locals[0] = 1.0;
+                locals[1] = -1.0;
+            }
+            Op::Mul => {
+                locals[0] = inputs[1];
+                locals[1] = inputs[0];
+            }
+            Op::Div => {
+                locals[0] = 1.0 / inputs[1];
+                locals[1] = -inputs[0] / (inputs[1] * inputs[1]);
+            }
+            Op::Neg => locals[0] = -1.0,
+            Op::Tanh => locals[0] = 1.0 - output * output,
+            Op::Exp => locals[0] = output,
+            Op::Pow(exponent) => locals[0] = exponent * inputs[0].powf(exponent - 1.0),
+            Op::Relu => locals[0] = if output > 0.0 { 1.0 } else { 0.0 },
+            Op::Log => locals[0] = 1.0 / inputs[0],
+            Op::Sqrt => locals[0] = 0.5 / output,
+            Op::Sin => locals[0] = inputs[0].cos(),
+            Op::Cos => locals[0] = -inputs[0].sin(),
+            Op::Sigmoid => locals[0] = output * (1.0 - output),
+            Op::Softplus => locals[0] = sigmoid(inputs[0]),
+            Op::Abs => locals[0] = sign(inputs[0]),
+            Op::LeakyRelu(alpha) => locals[0] = if inputs[0] > 0.0 { 1.0 } else { *alpha },
+            Op::Gelu => locals[0] = gelu_derivative(inputs[0]),
+            Op::PowValue => {
+                locals[0] = inputs[1] * inputs[0].powf(inputs[1] - 1.0);
+                locals[1] = pow_exponent_gradient(inputs[0], output);
+            }
+            // ties go to the lhs
+            Op::Max => {
+                let lhs = inputs[0] >= inputs[1];
+                locals[0] = if lhs { 1.0 } else { 0.0 };
+                locals[1] = if lhs { 0.0 } else { 1.0 };
+            }
+            Op::Min => {
+                let lhs = inputs[0] <= inputs[1];
+                locals[0] = if lhs { 1.0 } else { 0.0 };
+                locals[1] = if lhs { 0.0 } else { 1.0 };
+            }
+            Op::Atan2 => {
+                let (y, x) = (inputs[0], inputs[1]);
+                let r2 = x * x + y * y;
+                locals[0] = x / r2;
+                locals[1] = -y / r2;
+            }
+            Op::Custom(function) => {
+                for (local, gradient) in locals.iter_mut().zip(function.backward(inputs, output)) {
+                    *local = gradient;
+                }
+            }
+        }
+    }
+}
+
+/*scalar functions shared by the engines*/
+
+pub(crate) fn sigmoid(x: f64) -> f64 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+// ln(1 + e^x) without overflowing for large x
+pub(crate) fn softplus(x: f64) -> f64 {
+    x.max(0.0) + (-x.abs()).exp().ln_1p()
+}
+
+/// Subgradient of `abs`: 0 at 0.
+pub(crate) fn sign(x: f64) -> f64 {
+    if x > 0.0 {
+        1.0
+    } else if x < 0.0 {
+        -1.0
+    } else {
+        0.0
+    }
+}
+
+pub(crate) fn leaky_relu(x: f64, alpha: f64) -> f64 {
+    if x > 0.0 { x } else { alpha * x }
+}
+
+/// d(a^b)/db = a^b ln(a), taken as 0 for a zero base.
+pub(crate) fn pow_exponent_gradient(base: f64, output: f64) -> f64 {
+    if base == 0.0 { 0.0 } else { output * base.ln() }
+}
+
+// GELU uses the tanh approximation 0.5 x (1 + tanh(sqrt(2/pi) (x + 0.044715 x^3)))
+const GELU_COEFF: f64 = 0.044715;
+const SQRT_2_OVER_PI: f64 = FRAC_2_SQRT_PI * FRAC_1_SQRT_2;
+
+pub(crate) fn gelu(x: f64) -> f64 {
+    0.5 * x * (1.0 + (SQRT_2_OVER_PI * (x + GELU_COEFF * x.powi(3))).tanh())
+}
+
+pub(crate) fn gelu_derivative(x: f64) -> f64 {
+    let t = (SQRT_2_OVER_PI * (x + GELU_COEFF * x.powi(3))).tanh();
+    0.5 * (1.0 + t) + 0.5 * x * (1.0 - t * t) * SQRT_2_OVER_PI * (1.0 + 3.0 * GELU_COEFF * x * x)
+}
+
+/// A user-defined differentiable operation, applied with `Value::apply`.
+///
+/// `backward` returns the local derivative of the output with respect to each
+/// input; the engine multiplies them by the incoming gradient.
+pub trait Function: fmt::Debug + Send + Sync {
+    fn forward(&self, inputs: &[f64]) -> f64;
+
+    fn backward(&self, inputs: &[f64], output: f64) -> Vec<f64>;
+
+    /// Name shown on the node by `export_graph`.
+    fn name(&self) -> String;
+
+    /// Color of the node in `export_graph` (index into the set28 colorscheme).
+    fn color(&self) -> u16 {
+        7
+    }
 }
 
 
@@ -39,43 +249,272 @@ pub struct ValueData {
     children: Vec<Value>,
     gradient: f64,
     op: Op,
-    visited: bool,
     id: usize, //for sorting
     label: Option<String>, //usefull when using graphviz
+    hooks: Hooks,
 }
 
-static VAL_CNT: AtomicUsize = AtomicUsize::new(0);
+/// A closure registered with `Value::register_hook`.
+pub type Hook = Arc<dyn Fn(f64) -> f64 + Send + Sync>;
+
+#[derive(Clone, Default)]
+struct Hooks(Vec<Hook>);
 
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{} hooks]", self.0.len())
+    }
+}
+
+impl Drop for ValueData {
+    // Unlink the children iteratively: the default recursive drop overflows
+    // the stack on deep graphs (e.g. a long sum built in `Neuron::forward`).
+    fn drop(&mut self) {
+        let mut stack: Vec<Value> = std::mem::take(&mut self.children);
+        while let Some(node) = stack.pop() {
+            if let Ok(mutex) = Arc::try_unwrap(node.0) {
+                if let Ok(mut data) = mutex.into_inner() {
+                    stack.append(&mut data.children);
+                }
+            }
+        }
+    }
+}
+
+fn check_arity(op: &Op, found: usize) -> Result<(), BabygradError> {
+    match op.arity() {
+        Some(expected) if expected != found => Err(BabygradError::Arity {
+            op: op.name(),
+            expected,
+            found,
+        }),
+        _ => Ok(()),
+    }
+}
+
+thread_local! {
+    static GRAD_ENABLED: Cell<bool> = const { Cell::new(true) };
+}
+
+/// Returns false while a `NoGradGuard` is alive on the current thread.
+pub fn is_grad_enabled() -> bool {
+    GRAD_ENABLED.with(|enabled| enabled.get())
+}
+
+/// Disables graph building on the current thread until dropped: ops return
+/// constant leaves and don't keep their inputs alive, e.g. when evaluating a
+/// trained `MLP`.
+pub struct NoGradGuard {
+    previous: bool,
+}
+
+impl Default for NoGradGuard {
+    fn default() -> Self {
+        NoGradGuard::new()
+    }
+}
+
+impl NoGradGuard {
+    pub fn new() -> NoGradGuard {
+        NoGradGuard {
+            previous: GRAD_ENABLED.with(|enabled| enabled.replace(false)),
+        }
+    }
+}
+
+impl Drop for NoGradGuard {
+    fn drop(&mut self) {
+        GRAD_ENABLED.with(|enabled| enabled.set(self.previous));
+    }
+}
+
+/// Runs `f` under a `NoGradGuard`.
+pub fn no_grad<T>(f: impl FnOnce() -> T) -> T {
+    let _guard = NoGradGuard::new();
+    f()
+}
+
+thread_local! {
+    static DETECT_ANOMALY: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Returns true while an `AnomalyGuard` is alive on the current thread.
+pub fn is_anomaly_detection_enabled() -> bool {
+    DETECT_ANOMALY.with(|enabled| enabled.get())
+}
+
+/// Enables anomaly detection on the current thread until dropped: an op
+/// producing a NaN or infinite value panics when it is created, and so does
+/// `backward` at the first non-finite gradient, with an `Anomaly` telling
+/// which op, inputs and node it was. Every node is checked, which slows
+/// training down, so use it for debugging only.
+pub struct AnomalyGuard {
+    previous: bool,
+}
+
+impl Default for AnomalyGuard {
+    fn default() -> Self {
+        AnomalyGuard::new()
+    }
+}
+
+impl AnomalyGuard {
+    pub fn new() -> AnomalyGuard {
+        AnomalyGuard {
+            previous: DETECT_ANOMALY.with(|enabled| enabled.replace(true)),
+        }
+    }
+}
+
+impl Drop for AnomalyGuard {
+    fn drop(&mut self) {
+        DETECT_ANOMALY.with(|enabled| enabled.set(self.previous));
+    }
+}
+
+/// Runs `f` under an `AnomalyGuard`.
+pub fn detect_anomaly<T>(f: impl FnOnce() -> T) -> T {
+    let _guard = AnomalyGuard::new();
+    f()
+}
+
+/// The first non-finite number found in anomaly detection mode.
+#[derive(Clone, Debug)]
+pub struct Anomaly {
+    /// True for a gradient found by `backward`, false for the value of a new node.
+    pub backward: bool,
+    /// The op that computed the number: the op of the node itself for a
+    /// value, the op of the parent passing the gradient down for a gradient,
+    /// `hook` for a gradient returned by a hook of the node, `accumulate` for
+    /// a gradient added to by `Tape::backward_accumulate`.
+    pub op: &'static str,
+    /// The input values of `op`.
+    pub inputs: Vec<f64>,
+    /// The offending value or gradient.
+    pub number: f64,
+    /// Label of the node holding `number`.
+    pub label: Option<String>,
+    /// The nodes from the root of `backward` down to the offending one, as
+    /// labels, or op names for the unlabelled ones. Only the node itself for
+    /// a value.
+    pub path: Vec<String>,
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inputs: Vec<String> = self.inputs.iter().map(f64::to_string).collect();
+        write!(
+            f,
+            "anomaly: {} {} of {} node {} from inputs [{}], path {}",
+            self.number,
+            if self.backward { "gradient" } else { "value" },
+            self.op,
+            self.label.as_deref().unwrap_or("(unlabelled)"),
+            inputs.join(", "),
+            self.path.join(" -> ")
+        )
+    }
+}
+
+impl Error for Anomaly {}
+
+// ids of the nodes created outside of an `IdContext`, unique in the process
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static ID_CONTEXT: RefCell<Option<IdContext>> = const { RefCell::new(None) };
+}
+
+fn next_id() -> usize {
+    ID_CONTEXT.with(|context| match &*context.borrow() {
+        Some(context) => context.next.fetch_add(1, Ordering::Relaxed),
+        None => NEXT_ID.fetch_add(1, Ordering::Relaxed),
+    })
+}
+
+/// Owns a counter that numbers nodes from 0, e.g. one per model or training
+/// run. Giving every step the same ids (`restart` at its beginning) makes
+/// exported graphs comparable between runs.
+///
+/// The nodes created on a thread that has `enter`ed a context get their ids
+/// from it. Clones share the counter, so threads entering the same context
+/// get distinct ids. Outside of any context ids come from a process-wide
+/// counter and are unique. Ids are names for display and export: the graph
+/// algorithms tell nodes apart by identity.
+#[derive(Clone, Debug, Default)]
+pub struct IdContext {
+    next: Arc<AtomicUsize>,
+}
+
+impl IdContext {
+    pub fn new() -> IdContext {
+        IdContext::default()
+    }
+
+    /// Numbers the nodes created on the current thread with `self` until
+    /// the guard is dropped.
+    pub fn enter(&self) -> IdGuard {
+        IdGuard {
+            previous: ID_CONTEXT.with(|context| context.replace(Some(self.clone()))),
+        }
+    }
+
+    /// Runs `f` with `self` entered.
+    pub fn run<T>(&self, f: impl FnOnce() -> T) -> T {
+        let _guard = self.enter();
+        f()
+    }
+
+    /// Starts numbering from 0 again, e.g. at the beginning of a step.
+    pub fn restart(&self) {
+        self.next.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Returned by `IdContext::enter`, restores the context it replaced when dropped.
+pub struct IdGuard {
+    previous: Option<IdContext>,
+}
+
+impl Drop for IdGuard {
+    fn drop(&mut self) {
+        ID_CONTEXT.with(|context| *context.borrow_mut() = self.previous.take());
+    }
+}
+
+#[allow(dead_code)]
 impl ValueData {    
     fn new(value: f64) -> ValueData {            
         ValueData {
-            value: value,
+            value,
             children: vec![],
             gradient: 0.0,
             op: Op::None,
-            visited: false,
             id : 0,
-            label: None
+            label: None,
+            hooks: Hooks::default(),
         }
     }  
 }
 
 
+/// A node of the computation graph. Nodes are reference counted and locked
+/// with a `Mutex`, so graphs and models built from them are `Send + Sync`.
 #[allow(dead_code)]
 #[derive(Clone, Debug)]
-pub struct Value(Rc<RefCell<ValueData>>);
+pub struct Value(Arc<Mutex<ValueData>>);
 
 impl Default for  Value {
     fn default() -> Self {
-        let id = VAL_CNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst); 
-        Value(Rc::new(RefCell::new(ValueData {
+        let id = next_id();
+        Value(Arc::new(Mutex::new(ValueData {
             value: 0.0,
             children: vec![], //Vec::new(),
             gradient: 0.0,
             op: Op::None,
-            visited: false,
             id,
-            label: None
+            label: None,
+            hooks: Hooks::default(),
         })))
     }
 }
@@ -89,99 +528,203 @@ impl fmt::Display for Value {
 
 impl Value {
     pub fn new(value: f64) -> Value {
-        let id = VAL_CNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst); 
-        Value(Rc::new(RefCell::new(ValueData {
-            value: value,
+        let id = next_id();
+        Value(Arc::new(Mutex::new(ValueData {
+            value,
             children: vec![], //Vec::new(),
             gradient: 0.0,
             op: Op::None,
-            visited: false,
             id,
-            label: None
+            label: None,
+            hooks: Hooks::default(),
         })))
     }
 
     pub fn from(value: f64, children: Vec<Value>, op: Op) -> Value {
-        let id = VAL_CNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst); 
-        Value(Rc::new(RefCell::new(ValueData {
-            value: value,
-            children: children,
-            gradient: 0.0,
-            op: op,
-            visited: false,
-            id ,
-            label: None
-        })))
+        Value::from_with_label(value, children, op, None)
+    }
+
+    /// A leaf holding a scalar operand such as the `2.0` in `2.0 * x`.
+    pub fn constant(value: f64) -> Value {
+        Value::from(value, vec![], Op::Const)
+    }
+
+    /// `from` checking that `op` takes as many inputs as `children`.
+    pub fn try_new(value: f64, children: Vec<Value>, op: Op) -> Result<Value, BabygradError> {
+        Value::try_new_with_label(value, children, op, None)
+    }
+
+    pub fn try_new_with_label(
+        value: f64,
+        children: Vec<Value>,
+        op: Op,
+        label: Option<String>,
+    ) -> Result<Value, BabygradError> {
+        check_arity(&op, children.len())?;
+        Ok(Value::from_with_label(value, children, op, label))
     }
 
     pub fn from_with_label(value: f64, children: Vec<Value>, op: Op, s: Option<String>) -> Value {
-        let id = VAL_CNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst); 
-        Value(Rc::new(RefCell::new(ValueData {
-            value: value,
-            children: children,
+        // under `no_grad` the result of an op is a constant, its inputs are not kept
+        if !children.is_empty() && !is_grad_enabled() {
+            return Value::from_parts(value, vec![], Op::Const, s);
+        }
+        let node = Value::from_parts(value, children, op, s);
+        if is_anomaly_detection_enabled() && !value.is_finite() {
+            let data = node.inner();
+            if !data.children.is_empty() {
+                let anomaly = Anomaly {
+                    backward: false,
+                    op: data.op.name(),
+                    inputs: data.children.iter().map(Value::value).collect(),
+                    number: value,
+                    label: data.label.clone(),
+                    path: vec![node.describe(&data)],
+                };
+                drop(data);
+                panic!("{}", anomaly);
+            }
+        }
+        node
+    }
+
+    /// `from_with_label` without the `no_grad` check, to rebuild a graph as is.
+    pub(crate) fn from_parts(value: f64, children: Vec<Value>, op: Op, label: Option<String>) -> Value {
+        let id = next_id();
+        Value(Arc::new(Mutex::new(ValueData {
+            value,
+            children,
             gradient: 0.0,
-            op: op,
-            visited: false,
-            id ,
-            label: s
+            op,
+            id,
+            label,
+            hooks: Hooks::default(),
         })))
     }
 
-    
-    
-    pub fn inner(&self) -> Ref<ValueData> {       
-        self.0.borrow()
+    // a node in an `Anomaly` path, given its locked data
+    fn describe(&self, data: &ValueData) -> String {
+        match &data.label {
+            Some(label) => label.clone(),
+            None => data.op.name().to_owned(),
+        }
     }
 
-    pub fn inner_mut(&self) -> RefMut<ValueData> {        
-        self.0.borrow_mut()
+    /// A graph-free copy of `self`: a constant leaf with the same value and label.
+    pub fn detach(&self) -> Value {
+        Value::from_with_label(self.value(), vec![], Op::Const, self.label())
     }
 
-    fn child(&self, index: usize) -> Value {
-        self.0.borrow().children[index].clone()
+    pub fn inner(&self) -> MutexGuard<'_, ValueData> {       
+        self.0.lock().unwrap()
     }
 
-    fn only_child(&self) -> Value {
-        assert!(self.0.borrow().children.len() == 1);
-        self.child(0)
+    pub fn inner_mut(&self) -> MutexGuard<'_, ValueData> {        
+        self.0.lock().unwrap()
     }
 
-    fn lhs(&self) -> Value {
-        assert!(self.0.borrow().children.len() == 2);
-        self.child(0)
+    // child `index` of a node that must have `expected` children
+    fn child(&self, expected: usize, index: usize) -> Result<Value, BabygradError> {
+        let data = self.inner();
+        if data.children.len() != expected {
+            return Err(BabygradError::Arity {
+                op: data.op.name(),
+                expected,
+                found: data.children.len(),
+            });
+        }
+        Ok(data.children[index].clone())
     }
-    
-    fn rhs(&self) -> Value {
-        assert!(self.0.borrow().children.len() == 2);
-        self.child(1)
+
+    fn only_child(&self) -> Result<Value, BabygradError> {
+        self.child(1, 0)
     }
 
-    pub fn op(&self) -> Op {
-        self.0.borrow().op.clone()
+    fn lhs(&self) -> Result<Value, BabygradError> {
+        self.child(2, 0)
     }
 
-    fn visited(&self) -> bool {
-        self.0.borrow().visited
+    fn rhs(&self) -> Result<Value, BabygradError> {
+        self.child(2, 1)
+    }
+
+    pub fn children(&self) -> Vec<Value> {
+        self.inner().children.clone()
+    }
+
+    pub fn op(&self) -> Op {
+        self.inner().op.clone()
     }
 
     pub fn label(&self) -> Option<String> {
-        self.0.borrow().label.clone()
+        self.inner().label.clone()
+    }
+
+    pub fn set_label(&self, label: &str) {
+        self.inner_mut().label = Some(label.to_owned());
     }
 
     pub fn value(&self) -> f64 {
-        self.0.borrow().value
+        self.inner().value
     }
 
     pub fn gradient(&self) -> f64 {
-        self.0.borrow().gradient
+        self.inner().gradient
+    }
+
+    // `op` passes the gradient down, it is reported by anomaly detection
+    pub(crate) fn inc_gradient(&self, amount: f64, op: &'static str) {
+        let mut data = self.inner_mut();
+        let previous = data.gradient;
+        data.gradient += amount;
+        if is_anomaly_detection_enabled() && !data.gradient.is_finite() {
+            let anomaly = Anomaly {
+                backward: true,
+                op,
+                inputs: vec![previous, amount],
+                number: data.gradient,
+                label: data.label.clone(),
+                path: vec![self.describe(&data)],
+            };
+            drop(data);
+            panic!("{}", anomaly);
+        }
+    }
+
+    pub(crate) fn set_gradient(&self, gradient: f64) {
+        self.inner_mut().gradient = gradient
+    }
+
+    /// Registers a closure called by `backward` with the gradient of `self`
+    /// once it is complete. Its return value replaces the gradient before it
+    /// is propagated to the children, e.g. to log, clip or mask it. Hooks run
+    /// in registration order.
+    pub fn register_hook(&self, hook: impl Fn(f64) -> f64 + Send + Sync + 'static) {
+        self.inner_mut().hooks.0.push(Arc::new(hook));
     }
 
-    fn inc_gradient(&self, amount: f64) {
-        self.0.borrow_mut().gradient += amount
+    pub fn clear_hooks(&self) {
+        self.inner_mut().hooks.0.clear();
     }
 
-    fn zero_gradient(&self, amount: f64) {
-        self.0.borrow_mut().gradient = 0.0
+    // the gradient passed to the hooks, if there are any
+    fn run_hooks(&self) -> Option<f64> {
+        // don't hold the lock while calling out, a hook may read `self`
+        let hooks = self.inner().hooks.clone();
+        if hooks.0.is_empty() {
+            return None;
+        }
+        let received = self.gradient();
+        let mut gradient = received;
+        for hook in hooks.0.iter() {
+            gradient = hook(gradient);
+        }
+        self.set_gradient(gradient);
+        Some(received)
+    }
+
+    pub(crate) fn zero_gradient(&self) {
+        self.inner_mut().gradient = 0.0
     }
 
     pub fn vec(values: &[f64]) -> Vec<Value> {
@@ -192,172 +735,331 @@ impl Value {
     }
 
     pub fn id(&self) -> usize{
-        self.0.borrow().id
+        self.inner().id
     }
 
-    fn _reset_children_gradients_and_visited(node: &Value) {
-        for children in node.0.borrow().children.iter() {
-            children.0.borrow_mut().gradient = 0.0;
-            children.0.borrow_mut().visited = false;
-            Value::_reset_children_gradients_and_visited(children);
-        }
+    /// True if `self` and `other` are the same node (not merely equal ids or values).
+    pub fn ptr_eq(&self, other: &Value) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
     }
 
-    fn _find_leaf_nodes_not_visited(node: &Value) -> Vec<Value> {
-        let mut result: Vec<Value> = Vec::new();
-        if !node.visited() {
-            let mut count = 0;
-            for child in node.0.borrow().children.iter() {
-                if !child.visited() {
-                    result.append(&mut Value::_find_leaf_nodes_not_visited(child));
-                    count += 1;
-                }
+    /// Approximate heap memory owned by this node alone: its allocation and
+    /// the buffers of its children list, label and hooks.
+    pub(crate) fn heap_bytes(&self) -> usize {
+        let data = self.inner();
+        // the Arc allocation holds the strong and weak counts
+        2 * std::mem::size_of::<usize>()
+            + std::mem::size_of::<Mutex<ValueData>>()
+            + data.children.capacity() * std::mem::size_of::<Value>()
+            + data.label.as_ref().map_or(0, String::capacity)
+            + data.hooks.0.capacity() * std::mem::size_of::<Hook>()
+    }
+
+    /// Identity of the node for hash maps, valid as long as the node is alive.
+    pub(crate) fn key(&self) -> usize {
+        Arc::as_ptr(&self.0) as usize
+    }
+
+    /// Returns every node reachable from `self` in topological order, the root
+    /// first and the leaves last. Uses an explicit stack so deep graphs don't
+    /// overflow the call stack, and visits each node once (keyed by node
+    /// identity, not `id`, which can repeat across `IdContext`s).
+    pub fn topological_sort(&self) -> Vec<Value> {
+        let mut order: Vec<Value> = Vec::new();
+        let mut visited: HashSet<usize> = HashSet::new();
+        // (node, children_pushed): a node is emitted once all its children are.
+        let mut stack: Vec<(Value, bool)> = vec![(self.clone(), false)];
+        while let Some((node, children_pushed)) = stack.pop() {
+            if children_pushed {
+                order.push(node);
+                continue;
             }
-            if count == 0 {
-                node.0.borrow_mut().visited = true;
-                result.push(node.clone())
+            if !visited.insert(node.key()) {
+                continue;
+            }
+            stack.push((node.clone(), true));
+            for child in node.inner().children.iter() {
+                if !visited.contains(&child.key()) {
+                    stack.push((child.clone(), false));
+                }
             }
         }
-        result
+        order.reverse();
+        order
     }
-    
 
-    //we get a panic!
-    fn topological_sort(&self) -> Vec<Value> {
-        let mut parameters: Vec<Value> = Vec::new();
-        loop {
-            let mut leafs = Value::_find_leaf_nodes_not_visited(self);
-            if leafs.len() == 0 {
-                break;
-            }
-            println!("{}",leafs.len());
-            parameters.append(&mut leafs);
-        }        
-        parameters.reverse();
-        parameters
+    /// Backpropagates from `self`: resets the gradients of the whole graph,
+    /// seeds `self` with 1.0 and fills in all gradients in reverse
+    /// topological order.
+    ///
+    /// Panics on a node with the wrong number of inputs for its op (see
+    /// `validate`), and on the first non-finite gradient in anomaly
+    /// detection mode.
+    pub fn backward(&self) {
+        if let Err(error) = self.run_backward() {
+            panic!("{}", error);
+        }
     }
 
-    fn backward(&self) {
-        
-        Value::_reset_children_gradients_and_visited(self);
-
-        // Run topological sorting to compute the ordered list of parameters        
-        let mut parameters: Vec<Value> = Vec::new();
-        loop {
-            let mut leafs = Value::_find_leaf_nodes_not_visited(self);
-            if leafs.len() == 0 {
-                break;
+    /// `backward` for a graph that may be malformed: checks it with
+    /// `validate` first, and returns the anomaly found in anomaly detection
+    /// mode instead of panicking.
+    pub fn try_backward(&self) -> Result<(), BabygradError> {
+        self.validate()?;
+        self.run_backward()
+    }
+
+    /// Checks that every node reachable from `self` has as many inputs as
+    /// its op takes, which `Value::from` does not.
+    pub fn validate(&self) -> Result<(), BabygradError> {
+        for node in self.topological_sort() {
+            let data = node.inner();
+            check_arity(&data.op, data.children.len())?;
+        }
+        Ok(())
+    }
+
+    // stops at the first malformed node, and at the first non-finite
+    // gradient in anomaly detection mode
+    fn run_backward(&self) -> Result<(), BabygradError> {
+        let parameters = self.topological_sort();
+        for node in parameters.iter() {
+            node.zero_gradient();
+        }
+        self.inner_mut().gradient = 1.0;
+
+        // Fill in all the gradients in reverse topological order
+        let mut inputs: Vec<f64> = Vec::new();
+        let mut locals: Vec<f64> = Vec::new();
+        // in anomaly detection mode, the parent each node got its first
+        // gradient from, to report the path from the root
+        let detect = is_anomaly_detection_enabled();
+        let mut parents: HashMap<usize, Value> = HashMap::new();
+        for node in parameters {
+            // the gradient of `node` is complete here, let its hooks see it
+            if let Some(received) = node.run_hooks() {
+                let gradient = node.gradient();
+                if detect && !gradient.is_finite() {
+                    return Err(BabygradError::Anomaly(Anomaly {
+                        backward: true,
+                        op: "hook",
+                        inputs: vec![received],
+                        number: gradient,
+                        label: node.label(),
+                        path: node.anomaly_path(&parents),
+                    }));
+                }
+            }
+            let data = node.inner();
+            if data.children.is_empty() {
+                continue;
+            }
+            check_arity(&data.op, data.children.len())?;
+            inputs.clear();
+            inputs.extend(data.children.iter().map(Value::value));
+            locals.clear();
+            locals.resize(inputs.len(), 0.0);
+            data.op.local_gradients(&inputs, data.value, &mut locals);
+            let mut anomaly: Option<(Value, f64)> = None;
+            for (child, local) in data.children.iter().zip(locals.iter()) {
+                let amount = local * data.gradient;
+                if detect {
+                    parents.entry(child.key()).or_insert_with(|| node.clone());
+                    let gradient = child.gradient() + amount;
+                    if !gradient.is_finite() {
+                        anomaly = Some((child.clone(), gradient));
+                        break;
+                    }
+                }
+                child.inc_gradient(amount, data.op.name());
+            }
+            if let Some((child, gradient)) = anomaly {
+                let op = data.op.name();
+                // `anomaly_path` locks `node` again
+                drop(data);
+                let mut path = node.anomaly_path(&parents);
+                path.push(child.describe(&child.inner()));
+                return Err(BabygradError::Anomaly(Anomaly {
+                    backward: true,
+                    op,
+                    inputs: inputs.clone(),
+                    number: gradient,
+                    label: child.label(),
+                    path,
+                }));
             }
-            parameters.append(&mut leafs);
         }
-        parameters.reverse();
+        Ok(())
+    }
 
-        /* 
-        println!("{}", parameters.len()); 
-        for p in parameters.clone(){
-            println!("{}", p);
+    // `self` and its ancestors up to the root through `parents`, root first
+    fn anomaly_path(&self, parents: &HashMap<usize, Value>) -> Vec<String> {
+        let mut path = vec![self.describe(&self.inner())];
+        let mut next = parents.get(&self.key());
+        while let Some(node) = next {
+            path.push(node.describe(&node.inner()));
+            next = parents.get(&node.key());
         }
-        self.inner_mut().gradient = 1.0;*/
+        path.reverse();
+        path
+    }
 
-        parameters[0].0.borrow_mut().gradient = 1.0;
-        
+    /// Backpropagates like `backward` (the numeric gradients of the graph are
+    /// filled in as well), but builds every gradient as a `Value` graph and
+    /// returns those of `inputs`. The returned values can be differentiated
+    /// again, e.g. for second derivatives or Hessian-vector products.
+    ///
+    /// Gradients through `Op::Custom` nodes are treated as constants.
+    /// Panics on a malformed graph, like `backward`.
+    pub fn backward_create_graph(&self, inputs: &[Value]) -> Vec<Value> {
+        match self.try_backward_create_graph(inputs) {
+            Ok(gradients) => gradients,
+            Err(error) => panic!("{}", error),
+        }
+    }
 
-        // Fill in all the gradients in reverse topological order
+    /// `backward_create_graph` for a graph that may be malformed: checks it
+    /// with `validate` first.
+    pub fn try_backward_create_graph(&self, inputs: &[Value]) -> Result<Vec<Value>, BabygradError> {
+        self.validate()?;
+        let parameters = self.topological_sort();
+        let mut gradients: HashMap<usize, Value> = HashMap::new();
+        gradients.insert(self.key(), Value::constant(1.0));
 
         for node in parameters {
-            let out_gradient = node.gradient();
-            let out_value = node.value();
-
-            match node.op() {               
-                Op::Add => {
-                    node.lhs().inc_gradient(out_gradient);
-                    node.rhs().inc_gradient(out_gradient);
+            let out_gradient = match gradients.get(&node.key()) {
+                Some(gradient) => gradient.clone(),
+                None => continue,
+            };
+            node.inner_mut().gradient = out_gradient.value();
+
+            let locals: Vec<(Value, Value)> = match node.op() {
+                Op::None | Op::Const => vec![],
+                Op::Add => vec![
+                    (node.lhs()?, out_gradient.clone()),
+                    (node.rhs()?, out_gradient),
+                ],
+                Op::Sub => vec![
+                    (node.lhs()?, out_gradient.clone()),
+                    (node.rhs()?, -out_gradient),
+                ],
+                Op::Mul => vec![
+                    (node.lhs()?, out_gradient.clone() * node.rhs()?),
+                    (node.rhs()?, out_gradient * node.lhs()?),
+                ],
+                Op::Div => vec![
+                    (node.lhs()?, out_gradient.clone() / node.rhs()?),
+                    (node.rhs()?, -(out_gradient * node.lhs()?) / node.rhs()?.pow(2.0)),
+                ],
+                Op::Neg => vec![(node.only_child()?, -out_gradient)],
+                Op::Tanh => vec![(node.only_child()?, out_gradient * (1.0 - node.clone().pow(2.0)))],
+                Op::Exp => vec![(node.only_child()?, out_gradient * node.clone())],
+                Op::Pow(exponent) => vec![(
+                    node.only_child()?,
+                    out_gradient * exponent * node.only_child()?.pow(exponent - 1.0),
+                )],
+                Op::Relu => {
+                    let slope = if node.value() > 0.0 { 1.0 } else { 0.0 };
+                    vec![(node.only_child()?, out_gradient * slope)]
                 }
-                Op::Sub => {
-                    node.lhs().inc_gradient(out_gradient);
-                    node.rhs().inc_gradient(-out_gradient);
+                Op::Log => vec![(node.only_child()?, out_gradient / node.only_child()?)],
+                Op::Sqrt => vec![(node.only_child()?, out_gradient * 0.5 / node.clone())],
+                Op::Sin => vec![(node.only_child()?, out_gradient * node.only_child()?.cos())],
+                Op::Cos => vec![(node.only_child()?, -(out_gradient * node.only_child()?.sin()))],
+                Op::Sigmoid => vec![(node.only_child()?, out_gradient * node.clone() * (1.0 - node.clone()))],
+                Op::Softplus => vec![(node.only_child()?, out_gradient * node.only_child()?.sigmoid())],
+                Op::Abs => {
+                    let slope = sign(node.only_child()?.value());
+                    vec![(node.only_child()?, out_gradient * slope)]
                 }
-                Op::Mul => {
-                    node.lhs().inc_gradient(node.rhs().value() * out_gradient);
-                    node.rhs().inc_gradient(node.lhs().value() * out_gradient);
+                Op::LeakyRelu(alpha) => {
+                    let slope = if node.only_child()?.value() > 0.0 { 1.0 } else { alpha };
+                    vec![(node.only_child()?, out_gradient * slope)]
                 }
-                Op::Div => {
-                    let lhs_value = node.lhs().value();
-                    let rhs_value = node.rhs().value();
-                    node.lhs().inc_gradient(out_gradient / rhs_value);
-                    node.rhs().inc_gradient(-lhs_value * out_gradient / (rhs_value * rhs_value));
+                Op::Gelu => {
+                    let x = node.only_child()?;
+                    let t = ((x.clone() + x.clone().pow(3.0) * GELU_COEFF) * SQRT_2_OVER_PI).tanh();
+                    let slope = 0.5 * (1.0 + t.clone())
+                        + x.clone() * 0.5 * (1.0 - t.pow(2.0)) * SQRT_2_OVER_PI
+                            * (1.0 + x.clone().pow(2.0) * (3.0 * GELU_COEFF));
+                    vec![(x, out_gradient * slope)]
                 }
-                Op::Neg => {
-                    node.only_child().inc_gradient(-out_gradient);
+                Op::PowValue => {
+                    let (a, b) = (node.lhs()?, node.rhs()?);
+                    let db = if a.value() == 0.0 {
+                        Value::constant(0.0)
+                    } else {
+                        node.clone() * a.clone().log()
+                    };
+                    vec![
+                        (a.clone(), out_gradient.clone() * b.clone() * a.pow_value(b.clone() - 1.0)),
+                        (b, out_gradient * db),
+                    ]
                 }
-                Op::Tanh => {
-                    node.only_child()
-                        .inc_gradient((1.0 - out_value * out_value) * out_gradient);
+                Op::Max | Op::Min => {
+                    let (a, b) = (node.lhs()?, node.rhs()?);
+                    let lhs = match node.op() {
+                        Op::Max => a.value() >= b.value(),
+                        _ => a.value() <= b.value(),
+                    };
+                    let (da, db) = if lhs { (1.0, 0.0) } else { (0.0, 1.0) };
+                    vec![(a, out_gradient.clone() * da), (b, out_gradient * db)]
                 }
-                Op::Exp => {
-                    node.only_child().inc_gradient(out_value * out_gradient);
+                Op::Atan2 => {
+                    let (y, x) = (node.lhs()?, node.rhs()?);
+                    let r2 = x.clone().pow(2.0) + y.clone().pow(2.0);
+                    vec![
+                        (y.clone(), out_gradient.clone() * x.clone() / r2.clone()),
+                        (x, -(out_gradient * y) / r2),
+                    ]
                 }
-                Op::Pow => {
-                    let child_value = node.only_child().value();
-                    let exponent = out_value.log10() / child_value.log10();
-                    node.only_child()
-                        .inc_gradient(exponent * out_value / child_value * out_gradient);
+                Op::Custom(function) => {
+                    let children = node.inner().children.clone();
+                    let values: Vec<f64> = children.iter().map(Value::value).collect();
+                    let locals = function.backward(&values, node.value());
+                    children
+                        .into_iter()
+                        .zip(locals)
+                        .map(|(child, local)| (child, out_gradient.clone() * local))
+                        .collect()
                 }
-                _ => (),
-            }
-        }
-    } 
-
-     // Create a GraphViz dot format string representation of the graph.
-     // https://dreampuf.github.io/GraphvizOnline
-     pub fn export_graph(&self) -> String {    
-        fn inner(node: &Value) -> String {
-            let mut opstr : String = String::new();
-            let mut color : u16 = 0;
-            match node.op(){               
-                Op::Add  => { opstr ="+".to_owned(); color = 1; }
-                Op::Sub  => { opstr ="-".to_owned(); color = 1; }
-                Op::Mul => {  opstr = "*".to_owned(); color = 2;}
-                Op::Div => {  opstr= "/".to_owned(); color = 2; }
-                Op::Neg => {  opstr= "neg".to_owned(); color = 2; }
-                Op::Tanh => { opstr = "tanh".to_owned(); color = 3;}
-                Op::Exp => {  opstr= "exp".to_owned(); color = 4;  }
-                Op::Pow => {  opstr = "pow".to_owned(); color = 5; }
-                Op::Relu => { opstr = "relu".to_owned(); color = 6;}  
-                Op::None => { opstr = format!("v{}",node.id()).to_owned(); color = 0;}        
-                _ => (),
-            }
-            let id = node.id();      
-            let mut s = format!(
-                "{} [label=\"{{{} | {:.2} | {:.2}}}\", color={}];\n",
-                id,
-                match node.label() { Some(l) =>  l,  None => opstr},                
-                node.value(),
-                node.gradient(),               
-                color,
-            );
-            for prev in node.inner().children.iter() {
-                s.push_str(&inner(&prev));
-                s.push_str(&format!("{} -- {};\n", id, prev.inner().id));
+            };
+
+            for (child, gradient) in locals {
+                let total = match gradients.remove(&child.key()) {
+                    Some(previous) => previous + gradient,
+                    None => gradient,
+                };
+                gradients.insert(child.key(), total);
             }
-            s
         }
-    
-        let mut s = format!("strict graph {{\n");
-        s.push_str("rankdir=RL;\n");
-        s.push_str("node [shape=record,colorscheme=set28];\n");
-        s.push_str(&inner(&self));
-        s.push_str("}\n");
-        s
+
+        Ok(inputs
+            .iter()
+            .map(|input| match gradients.get(&input.key()) {
+                Some(gradient) => gradient.clone(),
+                None => Value::constant(0.0),
+            })
+            .collect())
     }
 
+
 }
 
 
 /*unary operations*/
 impl Value{
+    // the node of an op result: under `no_grad` a constant, built without
+    // collecting the inputs
+    fn op_result(value: f64, op: Op, children: impl FnOnce() -> Vec<Value>) -> Value {
+        if !is_grad_enabled() {
+            return Value::from_parts(value, vec![], Op::Const, None);
+        }
+        Value::from(value, children(), op)
+    }
+
     fn unary_op(self, op: Op, f: impl FnOnce(f64) -> f64) -> Value {
-        Value::from(f(self.value()), vec![self.clone()], op)
+        Value::op_result(f(self.value()), op, || vec![self])
     }
 
     pub fn tanh(self) -> Value {
@@ -373,13 +1075,97 @@ impl Value{
 
     pub fn pow(self, value: f64) -> Value {
         //Value::from(self.value().powf(value), vec![self.clone()], Op::Pow)
-        Value::from(self.value().powf(value), vec![self.clone()], Op::Pow)
+        self.unary_op(Op::Pow(value), |x| x.powf(value))
     }
 
     pub fn relu(self) -> Value {
         self.unary_op(Op::Relu, |x| x.max(0.0))
     }
 
+    /// Natural logarithm.
+    pub fn log(self) -> Value {
+        self.unary_op(Op::Log, f64::ln)
+    }
+
+    pub fn sqrt(self) -> Value {
+        self.unary_op(Op::Sqrt, f64::sqrt)
+    }
+
+    pub fn sin(self) -> Value {
+        self.unary_op(Op::Sin, f64::sin)
+    }
+
+    pub fn cos(self) -> Value {
+        self.unary_op(Op::Cos, f64::cos)
+    }
+
+    pub fn sigmoid(self) -> Value {
+        self.unary_op(Op::Sigmoid, sigmoid)
+    }
+
+    pub fn softplus(self) -> Value {
+        self.unary_op(Op::Softplus, softplus)
+    }
+
+    pub fn abs(self) -> Value {
+        self.unary_op(Op::Abs, f64::abs)
+    }
+
+    pub fn leaky_relu(self, alpha: f64) -> Value {
+        self.unary_op(Op::LeakyRelu(alpha), |x| leaky_relu(x, alpha))
+    }
+
+    /// GELU, using the tanh approximation.
+    pub fn gelu(self) -> Value {
+        self.unary_op(Op::Gelu, gelu)
+    }
+
+}
+
+
+/*binary operations*/
+impl Value{
+    fn binary_op(self, op: Op, rhs: Value, f: impl FnOnce(f64, f64) -> f64) -> Value {
+        Value::op_result(f(self.value(), rhs.value()), op, || vec![self, rhs])
+    }
+
+    // `binary_op` with a scalar operand, which only becomes a `Const` node
+    // when the graph is recorded
+    fn scalar_op(self, op: Op, rhs: f64, f: impl FnOnce(f64, f64) -> f64) -> Value {
+        Value::op_result(f(self.value(), rhs), op, || vec![self, Value::constant(rhs)])
+    }
+
+    fn scalar_lhs_op(self, op: Op, lhs: f64, f: impl FnOnce(f64, f64) -> f64) -> Value {
+        Value::op_result(f(lhs, self.value()), op, || vec![Value::constant(lhs), self])
+    }
+
+    /// `self` raised to the power `exponent`, differentiable in both.
+    pub fn pow_value(self, exponent: Value) -> Value {
+        self.binary_op(Op::PowValue, exponent, f64::powf)
+    }
+
+    /// The larger of `self` and `rhs`. The gradient goes to the selected
+    /// operand only, to `self` on a tie.
+    pub fn max(self, rhs: Value) -> Value {
+        self.binary_op(Op::Max, rhs, f64::max)
+    }
+
+    /// The smaller of `self` and `rhs`, see `max` for the gradient.
+    pub fn min(self, rhs: Value) -> Value {
+        self.binary_op(Op::Min, rhs, f64::min)
+    }
+
+    /// Four quadrant arctangent of `self / x`, like `f64::atan2`.
+    pub fn atan2(self, x: Value) -> Value {
+        self.binary_op(Op::Atan2, x, f64::atan2)
+    }
+
+    /// Applies a user-defined `Function` to `inputs`, recording it in the graph.
+    pub fn apply(function: Arc<dyn Function>, inputs: Vec<Value>) -> Value {
+        let values: Vec<f64> = inputs.iter().map(Value::value).collect();
+        Value::op_result(function.forward(&values), Op::Custom(function), || inputs)
+    }
+
 }
 
 
@@ -387,11 +1173,7 @@ impl ops::Add<Value> for Value {
     type Output = Value;
 
     fn add(self, rhs: Value) -> Value {
-        Value::from(
-            self.value() + rhs.value(),
-            vec![self.clone(), rhs.clone()],
-            Op::Add,
-        )
+        self.binary_op(Op::Add, rhs, |a, b| a + b)
     }
 }
 
@@ -399,11 +1181,7 @@ impl ops::Mul<Value> for Value {
     type Output = Value;
 
     fn mul(self, rhs: Value) -> Value {
-        Value::from(
-            self.value() * rhs.value(),
-            vec![self.clone(), rhs.clone()],
-            Op::Mul,
-        )
+        self.binary_op(Op::Mul, rhs, |a, b| a * b)
     }
 }
 
@@ -411,11 +1189,7 @@ impl ops::Div<Value> for Value {
     type Output = Value;
 
     fn div(self, rhs: Value) -> Value {
-        Value::from(
-            self.value() / rhs.value(),
-            vec![self.clone(), rhs.clone()],
-            Op::Div,
-        )
+        self.binary_op(Op::Div, rhs, |a, b| a / b)
     }
 }
 /* 
@@ -431,11 +1205,7 @@ impl ops::Sub<Value> for Value {
     type Output = Value;
 
     fn sub(self, rhs: Value) -> Value {
-        Value::from(
-            self.value() + (-1.0 * rhs.value()),
-            vec![self.clone(), rhs.clone()],
-            Op::Sub,
-        )
+        self.binary_op(Op::Sub, rhs, |a, b| a - b)
     }
 }
 
@@ -443,15 +1213,15 @@ impl ops::Neg for Value {
     type Output = Value;
 
     fn neg(self) -> Value {
-        Value::new(- self.value() )        
+        self.unary_op(Op::Neg, |x| -x)
     }
 }
 
-impl ops::Mul<Value> for f64 { //creates an issue, panic when sorting!
+impl ops::Mul<Value> for f64 {
     type Output = Value;
     
     fn mul(self, rhs: Value) -> Value {
-        Value::new(self * rhs.value())       
+        rhs.scalar_lhs_op(Op::Mul, self, |a, b| a * b)
     }
 } 
 
@@ -459,7 +1229,7 @@ impl ops::Add<Value> for f64 {
     type Output = Value;
     
     fn add(self, rhs: Value) -> Value {
-        Value::new(self + rhs.value())
+        rhs.scalar_lhs_op(Op::Add, self, |a, b| a + b)
     }
 }
 
@@ -467,7 +1237,7 @@ impl ops::Div<Value> for f64 {
     type Output = Value;
     
     fn div(self, rhs: Value) -> Value {
-        Value::new(self / rhs.value())
+        rhs.scalar_lhs_op(Op::Div, self, |a, b| a / b)
     }
 } 
 
@@ -475,7 +1245,7 @@ impl ops::Sub<Value> for f64 {
     type Output = Value;
     
     fn sub(self, rhs: Value) -> Value {
-        Value::new(self +(-1.0 * rhs.value()))
+        rhs.scalar_lhs_op(Op::Sub, self, |a, b| a - b)
     }
 } 
 
@@ -483,7 +1253,7 @@ impl ops::Add<f64> for Value {
     type Output = Value;
 
     fn add(self, rhs: f64) -> Value {
-        self + Value::new(rhs)
+        self.scalar_op(Op::Add, rhs, |a, b| a + b)
     }
 }
 
@@ -491,7 +1261,7 @@ impl ops::Sub<f64> for Value {
     type Output = Value;
 
     fn sub(self, rhs: f64) -> Value {
-        self + Value::new(-rhs)
+        self.scalar_op(Op::Sub, rhs, |a, b| a - b)
     }
 }
 
@@ -499,7 +1269,7 @@ impl ops::Mul<f64> for Value {
     type Output = Value;
 
     fn mul(self, rhs: f64) -> Value {
-        self * Value::new(rhs)
+        self.scalar_op(Op::Mul, rhs, |a, b| a * b)
     }
 
 }
@@ -508,7 +1278,7 @@ impl ops::Div<f64> for Value {
     type Output = Value;
 
     fn div(self, rhs: f64) -> Value {
-        self / Value::new(rhs)
+        self.scalar_op(Op::Div, rhs, |a, b| a / b)
     }
 
 }
@@ -523,13 +1293,7 @@ impl ops::Div<f64> for Value {
 #[cfg(test)]
 mod tests {
     use super::*;
-   
-
-    macro_rules! assert_approx {
-        ($a:expr , $b:expr) => {
-            assert!(($a - $b).abs() < 1e-8, "{} !~= {}", $a, $b);
-        };
-    }
+    use crate::testing::{assert_gradcheck, leaf};
 
     #[test]
     fn create_a_value() {
@@ -560,7 +1324,202 @@ mod tests {
         let a = Value::from(5.0,vec![],Op::None);
         let b = a.pow(2.0);        
         assert_eq!(b.value(), 25.0);
-        assert!(matches!(b.op(), Op::Pow));      
+        assert!(matches!(b.op(), Op::Pow(e) if e == 2.0));      
+    }
+
+    #[test]
+    fn log() {
+        let a = Value::from(5.0,vec![],Op::None);
+        let b = a.log();
+        assert_eq!(b.value(), 1.6094379124341003);
+        assert!(matches!(b.op(), Op::Log));
+    }
+
+    #[test]
+    fn sqrt() {
+        let a = Value::from(16.0,vec![],Op::None);
+        let b = a.sqrt();
+        assert_eq!(b.value(), 4.0);
+        assert!(matches!(b.op(), Op::Sqrt));
+    }
+
+    #[test]
+    fn sin_cos() {
+        let a = Value::from(1.0,vec![],Op::None);
+        let b = a.clone().sin();
+        let c = a.cos();
+        assert_eq!(b.value(), 0.8414709848078965);
+        assert_eq!(c.value(), 0.5403023058681398);
+        assert!(matches!(b.op(), Op::Sin));
+        assert!(matches!(c.op(), Op::Cos));
+    }
+
+    #[test]
+    fn sigmoid() {
+        let a = Value::from(0.0,vec![],Op::None);
+        let b = a.sigmoid();
+        assert_eq!(b.value(), 0.5);
+        assert!(matches!(b.op(), Op::Sigmoid));
+    }
+
+    #[test]
+    fn softplus() {
+        let a = Value::from(1.0,vec![],Op::None);
+        let b = a.softplus();
+        assert_approx!(b.value(), 1.0_f64.exp().ln_1p());
+        assert!(matches!(b.op(), Op::Softplus));
+        // no overflow for large inputs
+        assert_eq!(Value::new(1000.0).softplus().value(), 1000.0);
+    }
+
+    #[test]
+    fn abs() {
+        let a = Value::from(-3.0,vec![],Op::None);
+        let b = a.abs();
+        assert_eq!(b.value(), 3.0);
+        assert!(matches!(b.op(), Op::Abs));
+    }
+
+    #[test]
+    fn leaky_relu() {
+        let a = Value::from(-2.0,vec![],Op::None);
+        let b = a.leaky_relu(0.1);
+        assert_eq!(b.value(), -0.2);
+        assert!(matches!(b.op(), Op::LeakyRelu(alpha) if alpha == 0.1));
+        assert_eq!(Value::new(2.0).leaky_relu(0.1).value(), 2.0);
+    }
+
+    #[test]
+    fn gelu() {
+        let a = Value::from(1.0,vec![],Op::None);
+        let b = a.gelu();
+        assert_approx!(b.value(), 0.8411919906082768);
+        assert!(matches!(b.op(), Op::Gelu));
+        assert_eq!(Value::new(0.0).gelu().value(), 0.0);
+    }
+
+    type UnaryOp = fn(Value) -> Value;
+
+    fn unary_ops() -> Vec<(&'static str, UnaryOp)> {
+        vec![
+            ("log", Value::log),
+            ("sqrt", Value::sqrt),
+            ("sin", Value::sin),
+            ("cos", Value::cos),
+            ("sigmoid", Value::sigmoid),
+            ("softplus", Value::softplus),
+            ("abs", Value::abs),
+            ("leaky_relu 0.1", |x| x.leaky_relu(0.1)),
+            ("gelu", Value::gelu),
+        ]
+    }
+
+    #[test]
+    fn unary_backpropagation() {
+        for (name, f) in unary_ops() {
+            for x in [0.3, 1.7, -0.8] {
+                if (name == "log" || name == "sqrt") && x < 0.0 {
+                    continue;
+                }
+                assert_gradcheck(name, |a| f(a[0].clone()) * 2.0, &[x]);
+            }
+        }
+    }
+
+    #[test]
+    fn unary_second_derivatives() {
+        for (name, f) in unary_ops() {
+            let derivative = |a: &[Value]| f(a[0].clone()).backward_create_graph(a).remove(0);
+            let a = Value::new(0.6);
+            f(a.clone()).backward();
+            assert_approx!(derivative(std::slice::from_ref(&a)).value(), a.gradient());
+            assert_gradcheck(&format!("{}''", name), derivative, &[0.6]);
+        }
+    }
+
+    #[test]
+    fn unary_export_labels() {
+        for (name, f) in unary_ops() {
+            let b = f(Value::new(0.5));
+            assert!(b.export_graph().contains(&format!("label=\"{}\"", name)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn pow_value() {
+        let a = Value::new(2.0);
+        let b = Value::new(3.0);
+        let c = a.clone().pow_value(b.clone());
+        assert_eq!(c.value(), 8.0);
+        assert!(matches!(c.op(), Op::PowValue));
+
+        c.backward();
+        assert_approx!(a.gradient(), 12.0);
+        assert_approx!(b.gradient(), 8.0 * 2.0_f64.ln());
+
+        // zero base: no NaN from ln(0)
+        let a = Value::new(0.0);
+        let b = Value::new(2.0);
+        let c = a.clone().pow_value(b.clone());
+        c.backward();
+        assert_approx!(a.gradient(), 0.0);
+        assert_approx!(b.gradient(), 0.0);
+    }
+
+    #[test]
+    fn max_min() {
+        let a = Value::new(2.0);
+        let b = Value::new(-1.0);
+        let c = a.clone().max(b.clone()) * 3.0 + a.clone().min(b.clone()) * 5.0;
+        assert_approx!(c.value(), 1.0);
+        c.backward();
+        assert_approx!(a.gradient(), 3.0);
+        assert_approx!(b.gradient(), 5.0);
+
+        // a tie routes the gradient to the lhs only
+        let a = Value::new(1.0);
+        let b = Value::new(1.0);
+        let c = a.clone().max(b.clone());
+        assert!(matches!(c.op(), Op::Max));
+        c.backward();
+        assert_approx!(a.gradient(), 1.0);
+        assert_approx!(b.gradient(), 0.0);
+    }
+
+    #[test]
+    fn atan2() {
+        let y = Value::new(1.0);
+        let x = Value::new(-1.0);
+        let c = y.clone().atan2(x.clone());
+        assert_approx!(c.value(), 3.0 * std::f64::consts::FRAC_PI_4);
+        assert!(matches!(c.op(), Op::Atan2));
+        c.backward();
+        assert_approx!(y.gradient(), -0.5);
+        assert_approx!(x.gradient(), -0.5);
+    }
+
+    type BinaryOp = fn(Value, Value) -> Value;
+
+    #[test]
+    fn binary_ops_derivatives() {
+        let ops: Vec<(&str, BinaryOp)> = vec![
+            ("pow", Value::pow_value),
+            ("max", Value::max),
+            ("min", Value::min),
+            ("atan2", Value::atan2),
+        ];
+        for (name, f) in ops {
+            assert_gradcheck(name, |v| f(v[0].clone(), v[1].clone()), &[1.3, 0.7]);
+            // d/dx f * d/dy f, through the gradient graphs
+            let mixed = |v: &[Value]| {
+                let grads = f(v[0].clone(), v[1].clone()).backward_create_graph(v);
+                grads[0].clone() * grads[1].clone()
+            };
+            assert_gradcheck(&format!("{}''", name), mixed, &[1.3, 0.7]);
+
+            let c = f(Value::new(1.3), Value::new(0.7));
+            assert!(c.export_graph().contains(&format!("label=\"{}\"", name)), "{}", name);
+        }
     }
 
     #[test]
@@ -620,6 +1579,43 @@ mod tests {
       
     }
 
+    #[test]
+    fn test_arithmetic_operations_serie3_gradients() {
+        // scalar operands on the left must stay in the graph
+        let a = 2.0;
+        let b = Value::from(3.0, vec![], Op::None);
+
+        let result_add = a + b.clone();
+        result_add.backward();
+        assert_approx!(b.gradient(), 1.0);
+
+        let result_sub = a - b.clone();
+        result_sub.backward();
+        assert_approx!(b.gradient(), -1.0);
+
+        let result_mul = a * b.clone();
+        result_mul.backward();
+        assert_approx!(b.gradient(), 2.0);
+
+        let result_div = a / b.clone();
+        result_div.backward();
+        assert_approx!(b.gradient(), -2.0 / 9.0);
+        assert!(matches!(result_div.lhs().unwrap().op(), Op::Const));
+    }
+
+    #[test]
+    fn test_neg() {
+        let a = Value::from(3.0, vec![], Op::None);
+        let b = -a.clone();
+        assert_eq!(b.value(), -3.0);
+        assert!(matches!(b.op(), Op::Neg));
+
+        let c = b * 2.0 + 1.0;
+        c.backward();
+        assert_approx!(c.value(), -5.0);
+        assert_approx!(a.gradient(), -2.0);
+    }
+
     #[test]
     fn test_tanh() {
         let value = Value::from(1.0, vec![], Op::Tanh);
@@ -638,9 +1634,9 @@ mod tests {
 
     #[test]
     fn test_pow() {
-        let value = Value::from(2.0, vec![], Op::Pow);
+        let value = Value::from(2.0, vec![], Op::Pow(1.0));
         let result = value.clone().pow(3.0);
-        let expected = Value::from(2.0f64.powf(3.0), vec![value], Op::Pow);
+        let expected = Value::from(2.0f64.powf(3.0), vec![value], Op::Pow(3.0));
         assert_eq!(result.value(), expected.value());
     }
 
@@ -660,6 +1656,433 @@ mod tests {
 
 
 
+    #[test]
+    fn pow_backpropagation() {
+        // negative, zero and unit bases broke the old log10 based rule
+        for (base, exponent, expected) in [
+            (-2.0, 3.0, 12.0),
+            (0.0, 2.0, 0.0),
+            (1.0, 5.0, 5.0),
+            (4.0, 0.5, 0.25),
+            (2.0, -1.0, -0.25),
+        ] {
+            let a = Value::new(base);
+            let b = a.clone().pow(exponent);
+            b.backward();
+            assert_approx!(a.gradient(), expected);
+        }
+    }
+
+    #[test]
+    fn relu_backpropagation() {
+        let a = Value::new(3.0);
+        let b = Value::new(-2.0);
+        let c = (a.clone() * 2.0).relu() + (b.clone() * 2.0).relu();
+        c.backward();
+        assert_approx!(c.value(), 6.0);
+        assert_approx!(a.gradient(), 2.0);
+        assert_approx!(b.gradient(), 0.0);
+    }
+
+    #[derive(Debug)]
+    struct Softplus;
+
+    impl Function for Softplus {
+        fn forward(&self, inputs: &[f64]) -> f64 {
+            inputs[0].exp().ln_1p()
+        }
+
+        fn backward(&self, inputs: &[f64], _output: f64) -> Vec<f64> {
+            vec![1.0 / (1.0 + (-inputs[0]).exp())]
+        }
+
+        fn name(&self) -> String {
+            "softplus".to_owned()
+        }
+    }
+
+    // f(a, b) = a * b^2
+    #[derive(Debug)]
+    struct Kernel;
+
+    impl Function for Kernel {
+        fn forward(&self, inputs: &[f64]) -> f64 {
+            inputs[0] * inputs[1] * inputs[1]
+        }
+
+        fn backward(&self, inputs: &[f64], _output: f64) -> Vec<f64> {
+            vec![inputs[1] * inputs[1], 2.0 * inputs[0] * inputs[1]]
+        }
+
+        fn name(&self) -> String {
+            "kernel".to_owned()
+        }
+
+        fn color(&self) -> u16 {
+            8
+        }
+    }
+
+    #[test]
+    fn custom_function() {
+        let a = leaf(0.5, "a");
+        let b = Value::apply(Arc::new(Softplus), vec![a.clone()]);
+        assert_approx!(b.value(), 0.5_f64.exp().ln_1p());
+        assert!(matches!(b.op(), Op::Custom(_)));
+
+        let c = b * 2.0;
+        c.backward();
+        assert_approx!(a.gradient(), 2.0 / (1.0 + (-0.5_f64).exp()));
+        assert!(Value::export_graph(&c).contains("softplus"));
+    }
+
+    #[test]
+    fn custom_function_multiple_inputs() {
+        let kernel: Arc<dyn Function> = Arc::new(Kernel);
+        let a = Value::new(3.0);
+        let b = Value::new(-2.0);
+        let c = Value::apply(kernel.clone(), vec![a.clone(), b.clone()]);
+        let d = Value::apply(kernel, vec![c.clone(), Value::constant(1.0)]) + a.clone();
+        assert_approx!(c.value(), 12.0);
+        assert_approx!(d.value(), 15.0);
+
+        d.backward();
+        assert_approx!(a.gradient(), 5.0);
+        assert_approx!(b.gradient(), -12.0);
+        let dot = d.export_graph();
+        assert_eq!(dot.matches("[label=\"kernel\",shape=ellipse,color=8]").count(), 2, "{}", dot);
+        assert!(dot.contains("data 12.0000 | grad 1.0000"));
+    }
+
+    #[test]
+    fn second_derivatives() {
+        let x = Value::new(0.5);
+        let dx = x.clone().tanh().backward_create_graph(std::slice::from_ref(&x)).remove(0);
+        let t = 0.5_f64.tanh();
+        assert_approx!(dx.value(), 1.0 - t * t);
+        assert_approx!(x.gradient(), 1.0 - t * t);
+        dx.backward();
+        assert_approx!(x.gradient(), -2.0 * t * (1.0 - t * t));
+
+        let x = Value::new(1.5);
+        let dx = x.clone().exp().backward_create_graph(std::slice::from_ref(&x)).remove(0);
+        dx.backward();
+        assert_approx!(x.gradient(), 1.5_f64.exp());
+
+        let x = Value::new(-2.0);
+        let dx = x.clone().pow(3.0).backward_create_graph(std::slice::from_ref(&x)).remove(0);
+        assert_approx!(dx.value(), 12.0);
+        dx.backward();
+        assert_approx!(x.gradient(), -12.0);
+
+        // d/dx (1 / x) = -1 / x^2, d2/dx2 = 2 / x^3
+        let x = Value::new(2.0);
+        let dx = (1.0 / x.clone()).backward_create_graph(std::slice::from_ref(&x)).remove(0);
+        assert_approx!(dx.value(), -0.25);
+        dx.backward();
+        assert_approx!(x.gradient(), 0.25);
+    }
+
+    #[test]
+    fn hessian_vector_product() {
+        // f(x, y) = x^2 * y + y^3, H = [[2y, 2x], [2x, 6y]]
+        let x = Value::new(1.0);
+        let y = Value::new(2.0);
+        let f = x.clone().pow(2.0) * y.clone() + y.clone().pow(3.0);
+        let grads = f.backward_create_graph(&[x.clone(), y.clone()]);
+        assert_approx!(grads[0].value(), 4.0);
+        assert_approx!(grads[1].value(), 13.0);
+
+        let v = [3.0, -1.0];
+        let dot = grads[0].clone() * v[0] + grads[1].clone() * v[1];
+        dot.backward();
+        assert_approx!(x.gradient(), 4.0 * v[0] + 2.0 * v[1]);
+        assert_approx!(y.gradient(), 2.0 * v[0] + 12.0 * v[1]);
+    }
+
+    #[test]
+    fn gradient_penalty() {
+        // y = w * x^2, penalty = (dy/dx)^2 = 4 w^2 x^2, d penalty/dw = 8 w x^2
+        let w = Value::new(0.5);
+        let x = Value::new(3.0);
+        let y = w.clone() * x.clone().pow(2.0);
+        let dydx = y.backward_create_graph(std::slice::from_ref(&x)).remove(0);
+        let penalty = dydx.pow(2.0);
+        penalty.backward();
+        assert_approx!(penalty.value(), 9.0);
+        assert_approx!(w.gradient(), 36.0);
+
+        // inputs that don't take part in the graph get a zero gradient
+        let unused = Value::new(1.0);
+        let grads = y.backward_create_graph(&[unused]);
+        assert_approx!(grads[0].value(), 0.0);
+    }
+
+    #[test]
+    fn value_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Value>();
+
+        let a = Value::new(2.0);
+        let b = std::thread::spawn({
+            let a = a.clone();
+            move || a * 3.0
+        })
+        .join()
+        .unwrap();
+        b.backward();
+        assert_approx!(a.gradient(), 3.0);
+    }
+
+    #[test]
+    fn no_grad_builds_constants() {
+        let a = Value::new(2.0);
+        let b = no_grad(|| {
+            assert!(!is_grad_enabled());
+            (a.clone() * 3.0).tanh() + 1.0
+        });
+        assert!(is_grad_enabled());
+        assert_approx!(b.value(), 6.0_f64.tanh() + 1.0);
+        assert!(matches!(b.op(), Op::Const));
+        assert!(b.children().is_empty());
+
+        // leaves are still created as asked
+        let c = no_grad(|| Value::from(1.0, vec![], Op::None));
+        assert!(matches!(c.op(), Op::None));
+
+        b.backward();
+        assert_approx!(a.gradient(), 0.0);
+
+        // only the results are allocated, not the scalar operands
+        let ids = IdContext::new();
+        let after = ids.run(|| {
+            no_grad(|| (2.0 * a.clone() + 1.0).exp());
+            Value::new(0.0)
+        });
+        assert_eq!(after.id(), 3);
+    }
+
+    #[test]
+    fn no_grad_guard_nesting() {
+        {
+            let _outer = NoGradGuard::new();
+            {
+                let _inner = NoGradGuard::new();
+                assert!(!is_grad_enabled());
+            }
+            assert!(!is_grad_enabled());
+        }
+        assert!(is_grad_enabled());
+    }
+
+    fn panic_message(f: impl FnOnce() + std::panic::UnwindSafe) -> String {
+        let payload = std::panic::catch_unwind(f).unwrap_err();
+        payload.downcast_ref::<String>().cloned().unwrap()
+    }
+
+    #[test]
+    fn anomaly_in_forward() {
+        let x = leaf(1.0, "x");
+        let zero = Value::new(0.0);
+        // off by default
+        assert!((x.clone() / zero.clone()).value().is_infinite());
+
+        let message = panic_message(|| {
+            detect_anomaly(|| x.clone() * 2.0 / zero.clone());
+        });
+        assert!(message.contains("inf value of div"), "{}", message);
+        assert!(message.contains("inputs [2, 0]"), "{}", message);
+        assert!(!is_anomaly_detection_enabled());
+    }
+
+    #[test]
+    fn anomaly_in_backward() {
+        let x = leaf(0.0, "x");
+        let root = x.clone().sqrt();
+        root.set_label("root");
+        let h = root.clone() * 2.0;
+        h.set_label("h");
+        let out = h.tanh() + x.clone();
+        out.set_label("out");
+        // sqrt is finite at 0, its derivative is not
+        out.backward();
+        assert!(x.gradient().is_infinite());
+
+        let message = panic_message(|| {
+            let _guard = AnomalyGuard::new();
+            out.backward();
+        });
+        assert!(message.contains("inf gradient of sqrt node x from inputs [0]"), "{}", message);
+        assert!(message.ends_with("path out -> tanh -> h -> root -> x"), "{}", message);
+        assert!(!is_anomaly_detection_enabled());
+        // no lock was held by the panic
+        out.backward();
+        assert!(x.gradient().is_infinite());
+    }
+
+    #[test]
+    fn anomaly_in_hook() {
+        let x = leaf(2.0, "x");
+        let h = x.clone() * 3.0;
+        h.set_label("h");
+        let out = h.clone().tanh();
+        out.set_label("out");
+        h.register_hook(|gradient| gradient / 0.0);
+
+        match detect_anomaly(|| out.try_backward()) {
+            Err(BabygradError::Anomaly(anomaly)) => {
+                assert_eq!(anomaly.op, "hook");
+                assert_eq!(anomaly.inputs, vec![1.0 - 6.0_f64.tanh().powi(2)]);
+                assert_eq!(anomaly.label.as_deref(), Some("h"));
+                assert_eq!(anomaly.path, vec!["out", "h"]);
+            }
+            other => panic!("{:?}", other),
+        }
+        // not propagated to `x`
+        assert_eq!(x.gradient(), 0.0);
+        assert!(h.gradient().is_infinite());
+    }
+
+    #[test]
+    fn fallible_construction_and_backward() {
+        let x = Value::new(0.0);
+        let error = Value::try_new(1.0, vec![x.clone()], Op::Add).unwrap_err();
+        assert!(matches!(error, BabygradError::Arity { op: "add", expected: 2, found: 1 }));
+        assert_eq!(error.to_string(), "add takes 2 inputs, found 1");
+
+        // `from` does not check, `try_backward` does
+        let malformed = Value::from(1.0, vec![x.clone()], Op::Mul).tanh();
+        assert!(matches!(malformed.validate(), Err(BabygradError::Arity { op: "mul", .. })));
+        assert!(malformed.try_backward().is_err());
+        assert!(matches!(
+            malformed.try_backward_create_graph(std::slice::from_ref(&x)),
+            Err(BabygradError::Arity { op: "mul", expected: 2, found: 1 })
+        ));
+        assert_eq!(panic_message(|| malformed.backward()), "mul takes 2 inputs, found 1");
+        let message = panic_message(|| {
+            malformed.backward_create_graph(std::slice::from_ref(&x));
+        });
+        assert_eq!(message, "mul takes 2 inputs, found 1");
+
+        let out = Value::try_new(0.0, vec![x.clone()], Op::Sqrt).unwrap() * 2.0;
+        assert!(out.try_backward().is_ok());
+        assert!(x.gradient().is_infinite());
+        match detect_anomaly(|| out.try_backward()) {
+            Err(BabygradError::Anomaly(anomaly)) => {
+                assert!(anomaly.backward);
+                assert_eq!(anomaly.op, "sqrt");
+                assert_eq!(anomaly.path.len(), 3);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn id_contexts() {
+        let ids = IdContext::new();
+        let (w, out) = {
+            let _guard = ids.enter();
+            let w = Value::new(3.0);
+            assert_eq!(w.id(), 0);
+            ids.restart();
+            // same id as `w`, told apart by identity
+            let x = Value::new(2.0);
+            assert_eq!(x.id(), 0);
+            assert!(!x.ptr_eq(&w));
+            let out = x.clone() * w.clone() + x.clone();
+            assert_eq!(out.id(), 2);
+            (w, out)
+        };
+        assert_eq!(ids.run(|| Value::new(0.0).id()), 3);
+
+        // unique across threads, with or without a shared context
+        let shared = IdContext::new();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                std::thread::spawn(move || {
+                    let create = || (0..100).map(|_| Value::new(0.0).id()).collect::<Vec<usize>>();
+                    (create(), shared.run(create))
+                })
+            })
+            .collect();
+        let mut unscoped: HashSet<usize> = HashSet::new();
+        let mut scoped: HashSet<usize> = HashSet::new();
+        for thread in threads {
+            let (outside, inside) = thread.join().unwrap();
+            unscoped.extend(outside);
+            scoped.extend(inside);
+        }
+        assert_eq!(unscoped.len(), 400);
+        assert_eq!(scoped, (0..400).collect());
+
+        assert_eq!(out.topological_sort().len(), 4);
+        out.backward();
+        assert_approx!(w.gradient(), 2.0);
+        let grads = out.backward_create_graph(std::slice::from_ref(&w));
+        assert_approx!(grads[0].value(), 2.0);
+    }
+
+    #[test]
+    fn detach() {
+        let a = leaf(2.0, "a");
+        let b = a.clone() * a.clone();
+        let c = b.detach();
+        assert_approx!(c.value(), 4.0);
+        assert!(matches!(c.op(), Op::Const));
+        assert!(c.children().is_empty());
+        assert_ne!(c.id(), b.id());
+
+        let d = c * a.clone() + b;
+        d.backward();
+        assert_approx!(a.gradient(), 4.0 + 4.0);
+    }
+
+    #[test]
+    fn gradient_hooks() {
+        let a = Value::new(2.0);
+        let b = Value::new(-3.0);
+        let c = a.clone() * b.clone();
+        let d = c.clone() * 10.0;
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        c.register_hook(move |g| {
+            log.lock().unwrap().push(g);
+            g
+        });
+        // clip the gradient flowing into `a` and `b`
+        c.register_hook(|g| g.clamp(-1.0, 1.0));
+        d.backward();
+
+        assert_eq!(*seen.lock().unwrap(), vec![10.0]);
+        assert_approx!(c.gradient(), 1.0);
+        assert_approx!(a.gradient(), -3.0);
+        assert_approx!(b.gradient(), 2.0);
+
+        // hooks on leaves see the accumulated gradient
+        let e = a.clone() * a.clone() + a.clone();
+        a.register_hook(|g| g * 0.0);
+        e.backward();
+        assert_approx!(a.gradient(), 0.0);
+
+        a.clear_hooks();
+        e.backward();
+        assert_approx!(a.gradient(), 5.0);
+    }
+
+    #[test]
+    fn hook_can_read_its_node() {
+        let a = Value::new(2.0);
+        let b = a.clone().pow(2.0);
+        let node = b.clone();
+        b.register_hook(move |g| g * node.value());
+        b.backward();
+        assert_approx!(a.gradient(), 16.0);
+        // the hook owns a handle on `b`, break the cycle
+        b.clear_hooks();
+    }
+
     #[test]
     fn topological_order() {
         let v1 = Value::from_with_label(5.0,vec![],Op::None,Some("v1".to_owned()));
@@ -688,6 +2111,46 @@ mod tests {
 
     }
 
+    #[test]
+    fn backward_shared_node() {
+        let v1 = Value::from_with_label(5.0,vec![],Op::None,Some("v1".to_owned()));
+        let v2 = Value::from_with_label(1.0,vec![],Op::None,Some("v2".to_owned()));
+        let v3 = v1.clone() + v2.clone(); // 6
+        let v4 = v3.clone() * Value::new(2.0); // 12
+        let v5 = v3.clone() * Value::new(3.0); // 18
+        let v6 = v4 * v5; // 216 = 6 * v3^2
+
+        v6.backward();
+        assert_approx!(v3.gradient(), 72.0);
+        assert_approx!(v1.gradient(), 72.0);
+        assert_approx!(v2.gradient(), 72.0);
+
+        // gradients are reset, not accumulated, on a second pass
+        v6.backward();
+        assert_approx!(v1.gradient(), 72.0);
+    }
+
+    #[test]
+    fn backward_deep_chain() {
+        let n = 10_000;
+        let weights = Value::vec(&vec![0.5; n]);
+        let inputs = Value::vec(&vec![2.0; n]);
+        let mut output = Value::new(0.0);
+        for (input, weight) in inputs.iter().zip(weights.iter()) {
+            output = output + input.clone() * weight.clone();
+        }
+
+        let order = output.topological_sort();
+        assert_eq!(order.len(), 4 * n + 1);
+        assert!(order[0].ptr_eq(&output));
+
+        output.backward();
+        assert_approx!(output.value(), n as f64);
+        assert_approx!(weights[0].gradient(), 2.0);
+        assert_approx!(weights[n - 1].gradient(), 2.0);
+        assert_approx!(inputs[n / 2].gradient(), 0.5);
+    }
+
     #[test]
     fn abc_backpropagation1()
     {
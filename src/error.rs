@@ -0,0 +1,78 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::{Anomaly, JsonError, ParseError};
+
+/// Everything the fallible (`try_`) functions of the crate can report.
+#[derive(Clone, Debug)]
+pub enum BabygradError {
+    /// A node has the wrong number of inputs for its op.
+    Arity {
+        op: &'static str,
+        expected: usize,
+        found: usize,
+    },
+    /// Inputs given to a model that does not have as many weights.
+    Shape { expected: usize, found: usize },
+    /// A number of values that does not fill a shape: the elements of a
+    /// tensor, or the outputs of a model that should have one.
+    Elements { expected: usize, found: usize },
+    /// An axis past the dimensions of a tensor.
+    Axis { axis: usize, ndim: usize },
+    /// Tensor shapes that cannot be broadcast together, or multiplied for
+    /// a `matmul`.
+    Broadcast { lhs: Vec<usize>, rhs: Vec<usize> },
+    /// A model that cannot be built, e.g. without any layer.
+    Model(String),
+    /// A non-finite value or gradient found in anomaly detection mode.
+    Anomaly(Anomaly),
+    Parse(ParseError),
+    Json(JsonError),
+}
+
+impl fmt::Display for BabygradError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BabygradError::Arity { op, expected, found } => {
+                write!(f, "{} takes {} inputs, found {}", op, expected, found)
+            }
+            BabygradError::Shape { expected, found } => write!(f, "expected {} inputs, found {}", expected, found),
+            BabygradError::Elements { expected, found } => write!(f, "expected {} values, found {}", expected, found),
+            BabygradError::Axis { axis, ndim } => write!(f, "axis {} out of range for {} dimensions", axis, ndim),
+            BabygradError::Broadcast { lhs, rhs } => write!(f, "incompatible shapes {:?} and {:?}", lhs, rhs),
+            BabygradError::Model(message) => write!(f, "invalid model: {}", message),
+            BabygradError::Anomaly(anomaly) => anomaly.fmt(f),
+            BabygradError::Parse(error) => write!(f, "parse error at {}", error),
+            BabygradError::Json(error) => error.fmt(f),
+        }
+    }
+}
+
+impl Error for BabygradError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BabygradError::Anomaly(anomaly) => Some(anomaly),
+            BabygradError::Parse(error) => Some(error),
+            BabygradError::Json(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<Anomaly> for BabygradError {
+    fn from(anomaly: Anomaly) -> Self {
+        BabygradError::Anomaly(anomaly)
+    }
+}
+
+impl From<ParseError> for BabygradError {
+    fn from(error: ParseError) -> Self {
+        BabygradError::Parse(error)
+    }
+}
+
+impl From<JsonError> for BabygradError {
+    fn from(error: JsonError) -> Self {
+        BabygradError::Json(error)
+    }
+}
@@ -0,0 +1,197 @@
+use std::fmt;
+
+use crate::Value;
+
+/// Tolerances used by `gradcheck`. A leaf fails when
+/// `|analytic - numeric| > atol + rtol * |numeric|`.
+#[derive(Clone, Debug)]
+pub struct GradcheckOptions {
+    /// Step of the central difference.
+    pub eps: f64,
+    pub atol: f64,
+    pub rtol: f64,
+}
+
+impl Default for GradcheckOptions {
+    fn default() -> Self {
+        GradcheckOptions {
+            eps: 1e-6,
+            atol: 1e-5,
+            rtol: 1e-3,
+        }
+    }
+}
+
+/// A leaf whose `backward` gradient disagrees with the numeric estimate.
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    /// Position of the leaf in the `inputs` passed to `gradcheck`.
+    pub index: usize,
+    pub id: usize,
+    pub label: Option<String>,
+    pub analytic: f64,
+    pub numeric: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct GradcheckReport {
+    pub checked: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl GradcheckReport {
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+impl fmt::Display for GradcheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "gradcheck: {}/{} leaves match", self.checked - self.mismatches.len(), self.checked)?;
+        for m in self.mismatches.iter() {
+            writeln!(
+                f,
+                "  leaf {} ({}, id={}): analytic={}, numeric={}",
+                m.index,
+                m.label.as_deref().unwrap_or("unlabelled"),
+                m.id,
+                m.analytic,
+                m.numeric
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares the gradients computed by `backward` against central finite
+/// differences.
+///
+/// `f` builds the expression from the leaves it is given. It is called once on
+/// `inputs` for the analytic gradients, then twice per leaf on fresh copies of
+/// the leaves where that leaf is moved by `±eps`.
+pub fn gradcheck<F>(f: F, inputs: &[Value], options: &GradcheckOptions) -> GradcheckReport
+where
+    F: Fn(&[Value]) -> Value,
+{
+    let output = f(inputs);
+    // `backward` only resets the nodes it reaches, an input the output does
+    // not depend on would keep the gradient of an earlier pass (or one set
+    // by `f` itself, e.g. through `backward_create_graph`)
+    for input in inputs.iter() {
+        input.zero_gradient();
+    }
+    output.backward();
+
+    let values: Vec<f64> = inputs.iter().map(Value::value).collect();
+    let evaluate = |index: usize, delta: f64| -> f64 {
+        let mut perturbed = values.clone();
+        perturbed[index] += delta;
+        f(&Value::vec(&perturbed)).value()
+    };
+
+    let mut mismatches: Vec<Mismatch> = Vec::new();
+    for (index, input) in inputs.iter().enumerate() {
+        let analytic = input.gradient();
+        let numeric = (evaluate(index, options.eps) - evaluate(index, -options.eps)) / (2.0 * options.eps);
+        // written so that a NaN on either side counts as a mismatch
+        let close = (analytic - numeric).abs() <= options.atol + options.rtol * numeric.abs();
+        if !close {
+            mismatches.push(Mismatch {
+                index,
+                id: input.id(),
+                label: input.label(),
+                analytic,
+                numeric,
+            });
+        }
+    }
+
+    GradcheckReport {
+        checked: inputs.len(),
+        mismatches,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::leaf;
+    use crate::Function;
+    use std::sync::Arc;
+
+    #[test]
+    fn builtin_ops_pass() {
+        let inputs = vec![leaf(0.7, "a"), leaf(-1.3, "b"), leaf(2.1, "c")];
+        let report = gradcheck(
+            |x| {
+                let d = x[0].clone() * x[1].clone() + 2.0 * x[2].clone();
+                let e = (d.clone().tanh() + x[2].clone().pow(3.0)) / (1.0 + x[0].clone().exp());
+                e - (-x[1].clone()).relu() * 0.5
+            },
+            &inputs,
+            &GradcheckOptions::default(),
+        );
+        assert!(report.passed(), "{}", report);
+        assert_eq!(report.checked, 3);
+    }
+
+    #[test]
+    fn unused_inputs_with_stale_gradients() {
+        let inputs = vec![leaf(0.5, "x"), leaf(2.0, "unused")];
+        // an earlier graph left a gradient on the input `f` ignores
+        (inputs[1].clone() * 3.0).backward();
+        assert_eq!(inputs[1].gradient(), 3.0);
+
+        let report = gradcheck(|x| x[0].clone().tanh(), &inputs, &GradcheckOptions::default());
+        assert!(report.passed(), "{}", report);
+        assert_eq!(inputs[1].gradient(), 0.0);
+
+        // the derivative of |x| does not depend on x, `f` leaves a gradient on it
+        let inputs = vec![leaf(0.5, "x")];
+        let report = gradcheck(
+            |x| x[0].clone().abs().backward_create_graph(x).remove(0),
+            &inputs,
+            &GradcheckOptions::default(),
+        );
+        assert!(report.passed(), "{}", report);
+    }
+
+    #[derive(Debug)]
+    struct WrongSquare;
+
+    impl Function for WrongSquare {
+        fn forward(&self, inputs: &[f64]) -> f64 {
+            inputs[0] * inputs[0]
+        }
+
+        // missing factor 2
+        fn backward(&self, inputs: &[f64], _output: f64) -> Vec<f64> {
+            vec![inputs[0]]
+        }
+
+        fn name(&self) -> String {
+            "square".to_owned()
+        }
+    }
+
+    #[test]
+    fn reports_mismatching_leaves() {
+        let inputs = vec![leaf(1.5, "x"), leaf(3.0, "y")];
+        let report = gradcheck(
+            |x| Value::apply(Arc::new(WrongSquare), vec![x[0].clone()]) + x[1].clone(),
+            &inputs,
+            &GradcheckOptions::default(),
+        );
+        assert!(!report.passed());
+        assert_eq!(report.mismatches.len(), 1);
+
+        let mismatch = &report.mismatches[0];
+        assert_eq!(mismatch.index, 0);
+        assert_eq!(mismatch.id, inputs[0].id());
+        assert_eq!(mismatch.label.as_deref(), Some("x"));
+        assert!((mismatch.analytic - 1.5).abs() < 1e-8);
+        assert!((mismatch.numeric - 3.0).abs() < 1e-4);
+        assert!(report.to_string().contains(&format!("leaf 0 (x, id={}): analytic=1.5", inputs[0].id())));
+    }
+}
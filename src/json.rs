@@ -0,0 +1,583 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use crate::{Function, Op, Value};
+
+/// Why `Value::from_json` could not load a document.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JsonError {
+    /// Not valid JSON, `offset` is the byte position of the problem.
+    Syntax { offset: usize, message: String },
+    /// Valid JSON that does not describe a graph.
+    Graph(String),
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonError::Syntax { offset, message } => write!(f, "invalid JSON at byte {}: {}", offset, message),
+            JsonError::Graph(message) => write!(f, "invalid graph: {}", message),
+        }
+    }
+}
+
+impl Error for JsonError {}
+
+impl Value {
+    /// Serializes every node reachable from `self`, one per line, children
+    /// before their parents. The `"id"` of a node is its position in the
+    /// document:
+    ///
+    /// ```text
+    /// {
+    ///   "root": 2,
+    ///   "nodes": [
+    ///     {"id": 0, "op": "none", "value": 0.5, "gradient": 1.0, "label": "x", "children": []},
+    ///     {"id": 1, "op": "const", "value": 2.0, "gradient": 0.5, "label": null, "children": []},
+    ///     {"id": 2, "op": "mul", "value": 1.0, "gradient": 1.0, "label": null, "children": [0, 1]}
+    ///   ]
+    /// }
+    /// ```
+    ///
+    /// `pow` and `leaky_relu` carry their parameter in `"param"`, custom
+    /// functions their `name()` in `"name"`. Non-finite numbers are written
+    /// as the strings `"NaN"`, `"Infinity"` and `"-Infinity"`. Hooks are not
+    /// saved.
+    pub fn to_json(&self) -> String {
+        let mut order = self.topological_sort();
+        order.reverse();
+        let positions: HashMap<usize, usize> = order.iter().enumerate().map(|(i, node)| (node.key(), i)).collect();
+
+        let mut out = format!("{{\n  \"root\": {},\n  \"nodes\": [\n", order.len() - 1);
+        for (i, node) in order.iter().enumerate() {
+            let op = node.op();
+            write!(out, "    {{\"id\": {}, \"op\": \"{}\"", i, op.name()).unwrap();
+            match op {
+                Op::Pow(param) | Op::LeakyRelu(param) => write!(out, ", \"param\": {}", number(param)).unwrap(),
+                Op::Custom(function) => write!(out, ", \"name\": {}", string(&function.name())).unwrap(),
+                _ => {}
+            }
+            let label = node.label().map_or("null".to_owned(), |label| string(&label));
+            let children: Vec<String> = node.children().iter().map(|child| positions[&child.key()].to_string()).collect();
+            write!(
+                out,
+                ", \"value\": {}, \"gradient\": {}, \"label\": {}, \"children\": [{}]}}",
+                number(node.value()),
+                number(node.gradient()),
+                label,
+                children.join(", ")
+            )
+            .unwrap();
+            out.push_str(if i + 1 < order.len() { ",\n" } else { "\n" });
+        }
+        out.push_str("  ]\n}\n");
+        out
+    }
+
+    /// Rebuilds a graph saved by `to_json`, with its values, gradients and
+    /// labels. A node shared in the saved graph is shared in the new one.
+    /// The nodes get new ids, the `"id"`s of the document only link them.
+    /// Arrays and objects nested deeper than `MAX_DEPTH` are a syntax error.
+    pub fn from_json(src: &str) -> Result<Value, JsonError> {
+        Value::from_json_with(src, &HashMap::new())
+    }
+
+    /// `from_json` for graphs with custom functions, looked up by name.
+    pub fn from_json_with(src: &str, functions: &HashMap<String, Arc<dyn Function>>) -> Result<Value, JsonError> {
+        let document = Reader {
+            src,
+            offset: 0,
+            depth: 0,
+        }
+        .document()?;
+        let root = document.get("root").and_then(Json::id).ok_or_else(|| invalid("missing \"root\" id"))?;
+        let nodes = match document.get("nodes") {
+            Some(Json::Array(nodes)) => nodes,
+            _ => return Err(invalid("missing \"nodes\" array")),
+        };
+
+        let mut loaded: HashMap<usize, Value> = HashMap::with_capacity(nodes.len());
+        for node in nodes.iter() {
+            let id = node.get("id").and_then(Json::id).ok_or_else(|| invalid("node without an id"))?;
+            let field = |name: &str| node.get(name).ok_or_else(|| invalid(&format!("node {} has no \"{}\"", id, name)));
+            let float = |name: &str| {
+                field(name)?
+                    .float()
+                    .ok_or_else(|| invalid(&format!("\"{}\" of node {} is not a number", name, id)))
+            };
+
+            let op = match field("op")? {
+                Json::String(name) => op_from_name(name, || float("param"), || {
+                    let name = match field("name")? {
+                        Json::String(name) => name,
+                        _ => return Err(invalid(&format!("\"name\" of node {} is not a string", id))),
+                    };
+                    functions
+                        .get(name)
+                        .cloned()
+                        .ok_or_else(|| invalid(&format!("unknown custom function `{}`", name)))
+                })?,
+                _ => return Err(invalid(&format!("\"op\" of node {} is not a string", id))),
+            };
+            let label = match node.get("label") {
+                None | Some(Json::Null) => None,
+                Some(Json::String(label)) => Some(label.clone()),
+                Some(_) => return Err(invalid(&format!("\"label\" of node {} is not a string", id))),
+            };
+            let children = match field("children")? {
+                Json::Array(children) => children
+                    .iter()
+                    .map(|child| {
+                        let child = child.id().ok_or_else(|| invalid(&format!("bad child id in node {}", id)))?;
+                        loaded
+                            .get(&child)
+                            .cloned()
+                            .ok_or_else(|| invalid(&format!("node {} refers to node {} before it is defined", id, child)))
+                    })
+                    .collect::<Result<Vec<Value>, JsonError>>()?,
+                _ => return Err(invalid(&format!("\"children\" of node {} is not an array", id))),
+            };
+            if let Some(arity) = op.arity() {
+                if children.len() != arity {
+                    return Err(invalid(&format!(
+                        "node {} ({}) has {} children, expected {}",
+                        id,
+                        op.name(),
+                        children.len(),
+                        arity
+                    )));
+                }
+            }
+
+            let value = Value::from_parts(float("value")?, children, op, label);
+            value.set_gradient(float("gradient")?);
+            if loaded.insert(id, value).is_some() {
+                return Err(invalid(&format!("duplicate node id {}", id)));
+            }
+        }
+
+        loaded.remove(&root).ok_or_else(|| invalid(&format!("root {} is not in \"nodes\"", root)))
+    }
+}
+
+fn invalid(message: &str) -> JsonError {
+    JsonError::Graph(message.to_owned())
+}
+
+fn op_from_name(
+    name: &str,
+    param: impl FnOnce() -> Result<f64, JsonError>,
+    function: impl FnOnce() -> Result<Arc<dyn Function>, JsonError>,
+) -> Result<Op, JsonError> {
+    Ok(match name {
+        "none" => Op::None,
+        "const" => Op::Const,
+        "add" => Op::Add,
+        "mul" => Op::Mul,
+        "sub" => Op::Sub,
+        "div" => Op::Div,
+        "neg" => Op::Neg,
+        "tanh" => Op::Tanh,
+        "exp" => Op::Exp,
+        "pow" => Op::Pow(param()?),
+        "relu" => Op::Relu,
+        "log" => Op::Log,
+        "sqrt" => Op::Sqrt,
+        "sin" => Op::Sin,
+        "cos" => Op::Cos,
+        "sigmoid" => Op::Sigmoid,
+        "softplus" => Op::Softplus,
+        "abs" => Op::Abs,
+        "leaky_relu" => Op::LeakyRelu(param()?),
+        "gelu" => Op::Gelu,
+        "pow_value" => Op::PowValue,
+        "max" => Op::Max,
+        "min" => Op::Min,
+        "atan2" => Op::Atan2,
+        "custom" => Op::Custom(function()?),
+        _ => return Err(invalid(&format!("unknown op `{}`", name))),
+    })
+}
+
+fn number(value: f64) -> String {
+    if value.is_nan() {
+        "\"NaN\"".to_owned()
+    } else if value.is_infinite() {
+        if value > 0.0 { "\"Infinity\"" } else { "\"-Infinity\"" }.to_owned()
+    } else {
+        // `Debug` prints the shortest representation that reads back exactly
+        format!("{:?}", value)
+    }
+}
+
+fn string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A parsed JSON value, objects keep their keys in document order.
+#[allow(dead_code)]
+#[derive(Debug)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn id(&self) -> Option<usize> {
+        match self {
+            Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as usize),
+            _ => None,
+        }
+    }
+
+    fn float(&self) -> Option<f64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            Json::String(s) if s == "NaN" => Some(f64::NAN),
+            Json::String(s) if s == "Infinity" => Some(f64::INFINITY),
+            Json::String(s) if s == "-Infinity" => Some(f64::NEG_INFINITY),
+            _ => None,
+        }
+    }
+}
+
+/// How deeply `Value::from_json` lets arrays and objects nest, a saved
+/// graph needs 3 levels.
+pub const MAX_DEPTH: usize = 64;
+
+struct Reader<'s> {
+    src: &'s str,
+    offset: usize,
+    // arrays and objects open around the current position
+    depth: usize,
+}
+
+impl Reader<'_> {
+    fn document(mut self) -> Result<Json, JsonError> {
+        let document = self.value()?;
+        self.skip_whitespace();
+        if self.offset < self.src.len() {
+            return Err(self.error("trailing characters after the document"));
+        }
+        Ok(document)
+    }
+
+    fn error(&self, message: &str) -> JsonError {
+        JsonError::Syntax {
+            offset: self.offset,
+            message: message.to_owned(),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.offset..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.offset += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\n' | '\r')) {
+            self.offset += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), JsonError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.offset += 1;
+                Ok(())
+            }
+            _ => Err(self.error(&format!("expected `{}`", expected))),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, JsonError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{' | '[') if self.depth == MAX_DEPTH => {
+                Err(self.error(&format!("nested deeper than {} levels", MAX_DEPTH)))
+            }
+            Some('{') => {
+                self.depth += 1;
+                let object = self.object();
+                self.depth -= 1;
+                object
+            }
+            Some('[') => {
+                self.depth += 1;
+                let array = self.array();
+                self.depth -= 1;
+                array
+            }
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some('-' | '0'..='9') => self.number(),
+            Some(_) => {
+                for (word, value) in [("null", Json::Null), ("true", Json::Bool(true)), ("false", Json::Bool(false))] {
+                    if self.src[self.offset..].starts_with(word) {
+                        self.offset += word.len();
+                        return Ok(value);
+                    }
+                }
+                Err(self.error("expected a value"))
+            }
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, JsonError> {
+        self.expect('{')?;
+        let mut fields: Vec<(String, Json)> = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.offset += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some('"') {
+                return Err(self.error("expected a key"));
+            }
+            let key = self.string()?;
+            self.expect(':')?;
+            fields.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Json::Object(fields)),
+                _ => return Err(self.error("expected `,` or `}`")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, JsonError> {
+        self.expect('[')?;
+        let mut items: Vec<Json> = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.offset += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(Json::Array(items)),
+                _ => return Err(self.error("expected `,` or `]`")),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start = self.offset;
+        while matches!(self.peek(), Some('-' | '+' | '.' | 'e' | 'E' | '0'..='9')) {
+            self.offset += 1;
+        }
+        let text = &self.src[start..self.offset];
+        text.parse::<f64>().map(Json::Number).map_err(|_| JsonError::Syntax {
+            offset: start,
+            message: format!("invalid number `{}`", text),
+        })
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(out),
+                Some('\\') => match self.next() {
+                    Some('"') => out.push('"'),
+                    Some('\\') => out.push('\\'),
+                    Some('/') => out.push('/'),
+                    Some('b') => out.push('\u{8}'),
+                    Some('f') => out.push('\u{c}'),
+                    Some('n') => out.push('\n'),
+                    Some('r') => out.push('\r'),
+                    Some('t') => out.push('\t'),
+                    Some('u') => out.push(self.unicode_escape()?),
+                    _ => return Err(self.error("invalid escape")),
+                },
+                Some(c) => out.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    // after `\u`, including the second half of a surrogate pair
+    fn unicode_escape(&mut self) -> Result<char, JsonError> {
+        let high = self.hex4()?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            if !self.src[self.offset..].starts_with("\\u") {
+                return Err(self.error("unpaired surrogate"));
+            }
+            self.offset += 2;
+            let low = self.hex4()?;
+            0x10000 + ((high - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| self.error("invalid unicode escape"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let digits = self.src.get(self.offset..self.offset + 4).ok_or_else(|| self.error("invalid unicode escape"))?;
+        let code = u32::from_str_radix(digits, 16).map_err(|_| self.error("invalid unicode escape"))?;
+        self.offset += 4;
+        Ok(code)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Square;
+
+    impl Function for Square {
+        fn forward(&self, inputs: &[f64]) -> f64 {
+            inputs[0] * inputs[0]
+        }
+
+        fn backward(&self, inputs: &[f64], _output: f64) -> Vec<f64> {
+            vec![2.0 * inputs[0]]
+        }
+
+        fn name(&self) -> String {
+            "square".to_owned()
+        }
+    }
+
+    fn build() -> (Value, Value, Value) {
+        let a = Value::from_with_label(0.3, vec![], Op::None, Some("a \"quoted\"\n é".to_owned()));
+        let b = Value::new(-1.7);
+        let shared = (a.clone() * b.clone()).tanh();
+        let out = shared.clone().pow(2.0) + Value::apply(Arc::new(Square), vec![shared.clone()]).leaky_relu(0.01)
+            - b.clone().max(a.clone()) / 3.0;
+        (a, b, out)
+    }
+
+    #[test]
+    fn roundtrip() {
+        let (a, b, out) = build();
+        out.backward();
+        let json = out.to_json();
+
+        let square: Arc<dyn Function> = Arc::new(Square);
+        let functions: HashMap<String, Arc<dyn Function>> = [("square".to_owned(), square)].into_iter().collect();
+        let loaded = Value::from_json_with(&json, &functions).unwrap();
+
+        let order = loaded.topological_sort();
+        assert_eq!(order.len(), out.topological_sort().len());
+        assert_eq!(loaded.value(), out.value());
+        assert_eq!(loaded.gradient(), out.gradient());
+        // the saved document is the same up to the ids
+        assert_eq!(loaded.to_json().lines().count(), json.lines().count());
+
+        let leaves: Vec<Value> = order.iter().filter(|n| matches!(n.op(), Op::None)).cloned().collect();
+        let la = leaves.iter().find(|n| n.label().is_some()).unwrap();
+        let lb = leaves.iter().find(|n| n.label().is_none()).unwrap();
+        assert_eq!(la.label(), a.label());
+        assert_eq!(la.gradient(), a.gradient());
+
+        // the tanh node is shared: replaying backward gives the same gradients
+        loaded.backward();
+        assert_approx!(la.gradient(), a.gradient());
+        assert_approx!(lb.gradient(), b.gradient());
+    }
+
+    #[test]
+    fn non_finite_numbers() {
+        let out = Value::new(f64::NAN) + Value::constant(f64::NEG_INFINITY);
+        let json = out.to_json();
+        assert!(json.contains("\"value\": \"NaN\""));
+        assert!(json.contains("\"value\": \"-Infinity\""));
+        let loaded = Value::from_json(&json).unwrap();
+        assert!(loaded.value().is_nan());
+        assert_eq!(loaded.children()[1].value(), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn loads_under_no_grad() {
+        let out = Value::new(2.0).exp();
+        let loaded = crate::no_grad(|| Value::from_json(&out.to_json()).unwrap());
+        assert!(matches!(loaded.op(), Op::Exp));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |src: &str| Value::from_json(src).unwrap_err().to_string();
+        assert_eq!(error("{\"root\": 1, \"nodes\": [}"), "invalid JSON at byte 22: expected a value");
+        assert_eq!(error("{\"root\": 0} x"), "invalid JSON at byte 12: trailing characters after the document");
+        assert_eq!(error("{\"nodes\": []}"), "invalid graph: missing \"root\" id");
+        assert_eq!(
+            error(r#"{"root": 1, "nodes": [{"id": 1, "op": "neg", "value": 1, "gradient": 0, "children": [0]}]}"#),
+            "invalid graph: node 1 refers to node 0 before it is defined"
+        );
+        assert_eq!(
+            error(r#"{"root": 0, "nodes": [{"id": 0, "op": "add", "value": 1, "gradient": 0, "children": []}]}"#),
+            "invalid graph: node 0 (add) has 0 children, expected 2"
+        );
+        assert_eq!(
+            error(r#"{"root": 0, "nodes": [{"id": 0, "op": "custom", "name": "square", "value": 1, "gradient": 0, "children": []}]}"#),
+            "invalid graph: unknown custom function `square`"
+        );
+        assert_eq!(
+            error(r#"{"root": 0, "nodes": [{"id": 0, "op": "pow", "value": 1, "gradient": 0, "children": []}]}"#),
+            "invalid graph: node 0 has no \"param\""
+        );
+    }
+
+    #[test]
+    fn nesting_depth() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert_eq!(
+            Value::from_json(&nested(MAX_DEPTH)).unwrap_err(),
+            JsonError::Graph("missing \"root\" id".to_owned())
+        );
+        assert_eq!(
+            Value::from_json(&nested(100_000)).unwrap_err(),
+            JsonError::Syntax {
+                offset: MAX_DEPTH,
+                message: format!("nested deeper than {} levels", MAX_DEPTH),
+            }
+        );
+        let objects = "{\"a\": ".repeat(100_000);
+        assert!(matches!(Value::from_json(&objects), Err(JsonError::Syntax { offset, .. }) if offset == 6 * MAX_DEPTH));
+    }
+}
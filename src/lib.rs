@@ -0,0 +1,36 @@
+#[cfg(test)]
+#[macro_use]
+mod testing;
+
+pub mod dot;
+pub mod dual;
+pub mod engine;
+pub mod error;
+pub mod gradcheck;
+pub mod json;
+pub mod loss;
+pub mod nn;
+pub mod optimize;
+pub mod parse;
+pub mod program;
+pub mod stats;
+pub mod symbolic;
+pub mod tape;
+pub mod tensor;
+
+pub use dot::DotOptions;
+pub use dual::{jvp, Dual};
+pub use engine::{
+    detect_anomaly, no_grad, Anomaly, AnomalyGuard, Function, Hook, IdContext, IdGuard, NoGradGuard, Op, Value,
+};
+pub use error::BabygradError;
+pub use gradcheck::{gradcheck, GradcheckOptions};
+pub use json::JsonError;
+pub use loss::{LossOptions, Reduction};
+pub use optimize::optimize;
+pub use parse::ParseError;
+pub use program::Program;
+pub use stats::{GraphStats, StatsOptions};
+pub use symbolic::{derivative, expression, Expr};
+pub use tape::{Tape, Var};
+pub use tensor::Tensor;
@@ -0,0 +1,317 @@
+//! Losses over a batch of predictions. Each comes in three forms: `mse(..)`
+//! returns the mean over the samples as a single `Value`, `mse_with(..)`
+//! takes `LossOptions` for the sum or per-sample weights, and
+//! `mse_per_sample(..)` returns the unreduced loss of every sample.
+
+use crate::Value;
+
+/// How the per-sample losses are combined.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reduction {
+    /// Weighted mean: the sum of the weighted losses over the sum of the
+    /// weights, 0 when the weights sum to 0.
+    Mean,
+    Sum,
+}
+
+#[derive(Clone, Debug)]
+pub struct LossOptions {
+    pub reduction: Reduction,
+    /// One weight per sample multiplying its loss, all 1 when `None`.
+    pub weights: Option<Vec<f64>>,
+}
+
+impl Default for LossOptions {
+    fn default() -> Self {
+        LossOptions {
+            reduction: Reduction::Mean,
+            weights: None,
+        }
+    }
+}
+
+/// Smallest probability `bce` takes the log of.
+pub const BCE_EPS: f64 = 1e-7;
+
+/// Mean squared error.
+pub fn mse(predictions: &[Value], targets: &[Value]) -> Value {
+    mse_with(predictions, targets, &LossOptions::default())
+}
+
+pub fn mse_with(predictions: &[Value], targets: &[Value], options: &LossOptions) -> Value {
+    reduce(mse_per_sample(predictions, targets), options)
+}
+
+pub fn mse_per_sample(predictions: &[Value], targets: &[Value]) -> Vec<Value> {
+    pairwise(predictions, targets, |p, t| (p - t).pow(2.0))
+}
+
+/// Mean absolute error.
+pub fn mae(predictions: &[Value], targets: &[Value]) -> Value {
+    mae_with(predictions, targets, &LossOptions::default())
+}
+
+pub fn mae_with(predictions: &[Value], targets: &[Value], options: &LossOptions) -> Value {
+    reduce(mae_per_sample(predictions, targets), options)
+}
+
+pub fn mae_per_sample(predictions: &[Value], targets: &[Value]) -> Vec<Value> {
+    pairwise(predictions, targets, |p, t| (p - t).abs())
+}
+
+/// Squared error below `delta`, absolute error above it (continuous, with
+/// a continuous derivative), less sensitive to outliers than `mse`.
+pub fn huber(predictions: &[Value], targets: &[Value], delta: f64) -> Value {
+    huber_with(predictions, targets, delta, &LossOptions::default())
+}
+
+pub fn huber_with(predictions: &[Value], targets: &[Value], delta: f64, options: &LossOptions) -> Value {
+    reduce(huber_per_sample(predictions, targets, delta), options)
+}
+
+pub fn huber_per_sample(predictions: &[Value], targets: &[Value], delta: f64) -> Vec<Value> {
+    pairwise(predictions, targets, |p, t| {
+        let error = (p - t).abs();
+        if error.value() <= delta {
+            error.pow(2.0) * 0.5
+        } else {
+            (error - 0.5 * delta) * delta
+        }
+    })
+}
+
+/// Binary cross-entropy of probabilities, e.g. the output of `sigmoid`,
+/// against targets in [0, 1]. The probabilities are clamped to
+/// `[BCE_EPS, 1 - BCE_EPS]`, so a saturated prediction gives a large finite
+/// loss (and no gradient) instead of infinity. `bce_with_logits` on the
+/// input of the sigmoid has neither problem.
+pub fn bce(predictions: &[Value], targets: &[Value]) -> Value {
+    bce_with(predictions, targets, &LossOptions::default())
+}
+
+pub fn bce_with(predictions: &[Value], targets: &[Value], options: &LossOptions) -> Value {
+    reduce(bce_per_sample(predictions, targets), options)
+}
+
+pub fn bce_per_sample(predictions: &[Value], targets: &[Value]) -> Vec<Value> {
+    pairwise(predictions, targets, |p, t| {
+        let p = p.max(Value::constant(BCE_EPS)).min(Value::constant(1.0 - BCE_EPS));
+        -(t.clone() * p.clone().log() + (1.0 - t) * (1.0 - p).log())
+    })
+}
+
+/// `bce` of `sigmoid(logits)`, computed as `softplus(x) - x * t`: finite for
+/// any logit, with the gradient `sigmoid(x) - t`.
+pub fn bce_with_logits(logits: &[Value], targets: &[Value]) -> Value {
+    bce_with_logits_with(logits, targets, &LossOptions::default())
+}
+
+pub fn bce_with_logits_with(logits: &[Value], targets: &[Value], options: &LossOptions) -> Value {
+    reduce(bce_with_logits_per_sample(logits, targets), options)
+}
+
+pub fn bce_with_logits_per_sample(logits: &[Value], targets: &[Value]) -> Vec<Value> {
+    pairwise(logits, targets, |x, t| x.clone().softplus() - x * t)
+}
+
+/// Softmax cross-entropy of one vector of unnormalized scores per sample
+/// against the index of its class.
+pub fn cross_entropy(logits: &[Vec<Value>], classes: &[usize]) -> Value {
+    cross_entropy_with(logits, classes, &LossOptions::default())
+}
+
+pub fn cross_entropy_with(logits: &[Vec<Value>], classes: &[usize], options: &LossOptions) -> Value {
+    reduce(cross_entropy_per_sample(logits, classes), options)
+}
+
+pub fn cross_entropy_per_sample(logits: &[Vec<Value>], classes: &[usize]) -> Vec<Value> {
+    assert_eq!(logits.len(), classes.len(), "one class per sample");
+    logits
+        .iter()
+        .zip(classes)
+        .map(|(scores, &class)| {
+            assert!(class < scores.len(), "class {} out of {} scores", class, scores.len());
+            // shifted by the largest score so that `exp` cannot overflow
+            let shift = scores.iter().map(Value::value).fold(f64::NEG_INFINITY, f64::max);
+            let sum = scores
+                .iter()
+                .map(|score| (score.clone() - shift).exp())
+                .reduce(|sum, x| sum + x)
+                .unwrap();
+            sum.log() + shift - scores[class].clone()
+        })
+        .collect()
+}
+
+/// The max-margin loss of micrograd's demo, `relu(1 - label * score)` with
+/// labels -1 or 1.
+pub fn hinge(scores: &[Value], labels: &[Value]) -> Value {
+    hinge_with(scores, labels, &LossOptions::default())
+}
+
+pub fn hinge_with(scores: &[Value], labels: &[Value], options: &LossOptions) -> Value {
+    reduce(hinge_per_sample(scores, labels), options)
+}
+
+pub fn hinge_per_sample(scores: &[Value], labels: &[Value]) -> Vec<Value> {
+    pairwise(scores, labels, |score, label| (1.0 - label * score).relu())
+}
+
+/// `alpha` times the sum of the squared parameters, to add to a loss.
+pub fn l2_regularization(parameters: &[Value], alpha: f64) -> Value {
+    let sum = parameters
+        .iter()
+        .map(|p| p.clone().pow(2.0))
+        .reduce(|sum, x| sum + x)
+        .unwrap_or_else(|| Value::constant(0.0));
+    sum * alpha
+}
+
+fn pairwise(predictions: &[Value], targets: &[Value], loss: impl Fn(Value, Value) -> Value) -> Vec<Value> {
+    assert_eq!(predictions.len(), targets.len(), "one target per prediction");
+    predictions
+        .iter()
+        .zip(targets)
+        .map(|(p, t)| loss(p.clone(), t.clone()))
+        .collect()
+}
+
+fn reduce(losses: Vec<Value>, options: &LossOptions) -> Value {
+    let (losses, total): (Vec<Value>, f64) = match &options.weights {
+        Some(weights) => {
+            assert_eq!(weights.len(), losses.len(), "one weight per sample");
+            let weighted = losses.into_iter().zip(weights).map(|(loss, &w)| loss * w).collect();
+            (weighted, weights.iter().sum())
+        }
+        None => {
+            let total = losses.len() as f64;
+            (losses, total)
+        }
+    };
+    let Some(sum) = losses.into_iter().reduce(|sum, x| sum + x) else {
+        return Value::constant(0.0);
+    };
+    match options.reduction {
+        Reduction::Sum => sum,
+        // no sample counts
+        Reduction::Mean if total == 0.0 => Value::constant(0.0),
+        Reduction::Mean => sum / total,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nn::{ActivationFunc, MLP};
+
+    #[test]
+    fn regression_losses() {
+        let p = Value::vec(&[1.0, 2.0, 5.0]);
+        let t = Value::vec(&[1.5, 2.0, 2.0]);
+        assert_approx!(mse(&p, &t).value(), (0.25 + 9.0) / 3.0);
+        assert_approx!(mae(&p, &t).value(), 3.5 / 3.0);
+        // quadratic under delta, linear above
+        assert_approx!(huber(&p, &t, 1.0).value(), (0.125 + 2.5) / 3.0);
+
+        let loss = mse(&p, &t);
+        loss.backward();
+        assert_approx!(p[2].gradient(), 2.0 * 3.0 / 3.0);
+        let loss = huber(&p, &t, 1.0);
+        loss.backward();
+        assert_approx!(p[0].gradient(), -0.5 / 3.0);
+        assert_approx!(p[2].gradient(), 1.0 / 3.0);
+    }
+
+    #[test]
+    fn reductions_and_weights() {
+        let p = Value::vec(&[1.0, 2.0, 5.0]);
+        let t = Value::vec(&[0.0, 0.0, 0.0]);
+        let losses: Vec<f64> = mae_per_sample(&p, &t).iter().map(Value::value).collect();
+        assert_eq!(losses, vec![1.0, 2.0, 5.0]);
+
+        let sum = LossOptions {
+            reduction: Reduction::Sum,
+            weights: Some(vec![1.0, 0.0, 2.0]),
+        };
+        assert_eq!(mae_with(&p, &t, &sum).value(), 11.0);
+        let mean = LossOptions {
+            weights: Some(vec![1.0, 0.0, 2.0]),
+            ..LossOptions::default()
+        };
+        assert_approx!(mae_with(&p, &t, &mean).value(), 11.0 / 3.0);
+        assert_eq!(mse(&[], &[]).value(), 0.0);
+
+        let nothing = LossOptions {
+            weights: Some(vec![0.0, 0.0, 0.0]),
+            ..LossOptions::default()
+        };
+        let loss = mae_with(&p, &t, &nothing);
+        assert_eq!(loss.value(), 0.0);
+        loss.backward();
+        assert_eq!(p[2].gradient(), 0.0);
+    }
+
+    #[test]
+    fn classification_losses() {
+        let p = Value::vec(&[0.9, 0.2]);
+        let t = Value::vec(&[1.0, 0.0]);
+        assert_approx!(bce(&p, &t).value(), -(0.9_f64.ln() + 0.8_f64.ln()) / 2.0);
+
+        // saturated predictions stay finite
+        let certain = Value::vec(&[1.0, 0.0]);
+        let loss = bce(&certain, &Value::vec(&[0.0, 0.0]));
+        let high = 1.0 - BCE_EPS;
+        assert_approx!(loss.value(), -((1.0 - high).ln() + high.ln()) / 2.0);
+        loss.backward();
+        assert_eq!(certain[0].gradient(), 0.0);
+
+        // bce_with_logits agrees with bce of the sigmoid, and is exact past it
+        let x = Value::vec(&[0.0, 2.0, -3.0, 800.0]);
+        let t = Value::vec(&[1.0, 0.0, 0.5, 0.0]);
+        let loss = bce_with_logits(&x, &t);
+        let sigmoids: Vec<Value> = x[..3].iter().map(|x| x.clone().sigmoid()).collect();
+        let expected = bce_per_sample(&sigmoids, &t[..3]);
+        let per_sample = bce_with_logits_per_sample(&x, &t);
+        for (loss, expected) in per_sample.iter().zip(expected.iter()) {
+            assert_approx!(loss.value(), expected.value());
+        }
+        assert_approx!(per_sample[3].value(), 800.0);
+        loss.backward();
+        for (x, t) in x.iter().zip(t.iter()) {
+            assert_approx!(x.gradient(), (crate::engine::sigmoid(x.value()) - t.value()) / 4.0);
+        }
+
+        let logits = vec![Value::vec(&[1.0, 2.0, 3.0]), Value::vec(&[1000.0, 0.0, 0.0])];
+        let loss = cross_entropy(&logits, &[2, 0]);
+        let log_sum = (1.0_f64.exp() + 2.0_f64.exp() + 3.0_f64.exp()).ln();
+        assert_approx!(loss.value(), (log_sum - 3.0) / 2.0);
+        loss.backward();
+        // softmax minus the one-hot target
+        assert_approx!(logits[0][0].gradient(), 1.0_f64.exp() / log_sum.exp() / 2.0);
+        assert_approx!(logits[0][2].gradient(), (3.0_f64.exp() / log_sum.exp() - 1.0) / 2.0);
+        assert!(logits[1][0].gradient().abs() < 1e-12);
+
+        let scores = Value::vec(&[2.0, 0.5, -0.5]);
+        let labels = Value::vec(&[1.0, 1.0, 1.0]);
+        assert_approx!(hinge(&scores, &labels).value(), (0.5 + 1.5) / 3.0);
+    }
+
+    #[test]
+    fn hinge_loss_on_mlp() {
+        let xs: &[&[f64]] = &[&[2.0, 3.0, -1.0], &[3.0, -1.0, 0.5], &[0.5, 1.0, 1.0], &[1.0, 1.0, -1.0]];
+        let labels = Value::vec(&[1.0, -1.0, -1.0, 1.0]);
+        let mlp = MLP::new(3, &[4, 4], ActivationFunc::None);
+        let scores: Vec<Value> = xs.iter().map(|x| mlp.forward(Value::vec(x))).collect();
+        let parameters = mlp.parameters();
+        let total = hinge(&scores, &labels) + l2_regularization(&parameters, 1e-4);
+        total.backward();
+
+        // samples inside the margin push their score towards their label
+        for (score, label) in scores.iter().zip(labels.iter()) {
+            let expected = if label.value() * score.value() < 1.0 { -label.value() / 4.0 } else { 0.0 };
+            assert_approx!(score.gradient(), expected);
+        }
+        let reg = l2_regularization(&parameters[..1], 0.5);
+        assert_approx!(reg.value(), 0.5 * parameters[0].value().powi(2));
+    }
+}
@@ -1,42 +1,38 @@
 
-mod engine;
-mod nn;
-
-use engine::Value;
-use nn::Neuron;
-
-
 fn main() {   
     println!("Hello, this is babygrad!");
     println!("Run: cargo test -- --nocapture");
     println!("Go to: https://dreampuf.github.io/GraphvizOnline");
     println!("Copy paste the Graphviz dot output to visualise.");
     /*
-    strict graph {
-    rankdir=RL;
-    node [shape=record,colorscheme=set28];
-    84 [label="{* | 216.00 | 0.00}", color=2];
-    81 [label="{* | 12.00 | 0.00}", color=2];
-    79 [label="{+ | 6.00 | 0.00}", color=1];
-    77 [label="{v1 | 5.00 | 0.00}", color=0];
-    79 -- 77;
-    78 [label="{v2 | 1.00 | 0.00}", color=0];
-    79 -- 78;
-    81 -- 79;
-    80 [label="{vx | 2.00 | 0.00}", color=0];
-    81 -- 80;
-    84 -- 81;
-    83 [label="{* | 18.00 | 0.00}", color=2];
-    79 [label="{+ | 6.00 | 0.00}", color=1];
-    77 [label="{v1 | 5.00 | 0.00}", color=0];
-    79 -- 77;
-    78 [label="{v2 | 1.00 | 0.00}", color=0];
-    79 -- 78;
-    83 -- 79;
-    82 [label="{vy | 3.00 | 0.00}", color=0];
-    83 -- 82;
-    84 -- 83;
-    } 
+    digraph  {
+      rankdir=LR
+      node[shape=record,colorscheme=set28]
+      v0[label="{vy | data 3.0000 | grad 0.0000}"]
+      v1[label="{v2 | data 1.0000 | grad 0.0000}"]
+      v2[label="{v1 | data 5.0000 | grad 0.0000}"]
+      v3[label="{ | data 6.0000 | grad 0.0000}"]
+      op3[label="+",shape=ellipse,color=1]
+      op3 -> v3
+      v2 -> op3
+      v1 -> op3
+      v4[label="{ | data 18.0000 | grad 0.0000}"]
+      op4[label="*",shape=ellipse,color=2]
+      op4 -> v4
+      v3 -> op4
+      v0 -> op4
+      v5[label="{vx | data 2.0000 | grad 0.0000}"]
+      v6[label="{ | data 12.0000 | grad 0.0000}"]
+      op6[label="*",shape=ellipse,color=2]
+      op6 -> v6
+      v3 -> op6
+      v5 -> op6
+      v7[label="{ | data 216.0000 | grad 0.0000}"]
+      op7[label="*",shape=ellipse,color=2]
+      op7 -> v7
+      v6 -> op7
+      v4 -> op7
+    }
     */
 }
 
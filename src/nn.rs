@@ -1,9 +1,7 @@
 use rand::distributions::{Distribution, Uniform};
 use std::iter::zip;
-//mod engine;
-
-//use engine::Value;
-use crate::Value;
+use crate::{BabygradError, Tensor, Value};
+use crate::tape::{Tape, Var};
 use std::fmt;
 
 
@@ -70,9 +68,9 @@ impl Neuron {
         }
 
         Neuron{ 
-            weights : weights,
+            weights,
             bias: Value::new(0.0), //Value::new(uniform.sample(&mut rng)),
-            nonlin : nonlin,           
+            nonlin,           
         }
     }
 
@@ -81,21 +79,49 @@ impl Neuron {
         for (input, weight) in zip(inputs, self.weights.iter()) {
             output = output + input * weight.clone();
         }       
-        if self.nonlin == false {
-            return output;
+        if !self.nonlin {
+            output
         }
         else{
             match act {  
-                //ActivationFunc::Relu => println!("Applying ReLU activation function"),            
                 //ActivationFunc::Linear => println!("Applying Linear activation function"),
-                //ActivationFunc::Sigmoid => println!("Applying Sigmoid activation function"),
-                //ActivationFunc::Gelu => println!("Applying GELU activation function"),          
                 ActivationFunc::Tanh => output.tanh(),
+                ActivationFunc::Relu => output.relu(),
+                ActivationFunc::Sigmoid => output.sigmoid(),
+                ActivationFunc::Gelu => output.gelu(),
                 _ => output,          
             }   
         }   
     }
 
+    /// `forward` checking that there is one input per weight, `forward`
+    /// ignores the extra inputs or weights.
+    pub fn try_forward(&self, inputs: Vec<Value>, act: ActivationFunc) -> Result<Value, BabygradError> {
+        check_inputs(self.weights.len(), inputs.len())?;
+        Ok(self.forward(inputs, act))
+    }
+
+    /// Same as `forward`, evaluated on a `Tape`. The weights and bias are
+    /// loaded as tape parameters, so `Tape::backward` fills in their gradients.
+    pub fn forward_tape<'t>(&self, tape: &'t Tape, inputs: &[Var<'t>], act: ActivationFunc) -> Var<'t> {
+        let mut output = tape.param(&self.bias);
+        for (input, weight) in zip(inputs, self.weights.iter()) {
+            output = output + *input * tape.param(weight);
+        }
+        if !self.nonlin {
+            output
+        }
+        else{
+            match act {
+                ActivationFunc::Tanh => output.tanh(),
+                ActivationFunc::Relu => output.relu(),
+                ActivationFunc::Sigmoid => output.sigmoid(),
+                ActivationFunc::Gelu => output.gelu(),
+                _ => output,
+            }
+        }
+    }
+
     pub fn parameters(&self) -> Vec<Value> {
         let mut result = self.weights.clone();
         result.push(self.bias.clone());
@@ -146,15 +172,21 @@ pub struct Layer {
 
 #[allow(dead_code)]
 impl Layer {
-    fn new(input_size: usize, output_size: usize) -> Layer {
+    pub fn new(input_size: usize, output_size: usize) -> Layer {
+        Layer::with_nonlin(input_size, output_size, false)
+    }
+
+    /// A layer of neurons that apply the activation passed to `forward`
+    /// when `nonlin` is set, like the hidden layers of an `MLP`.
+    pub fn with_nonlin(input_size: usize, output_size: usize, nonlin: bool) -> Layer {
         let mut neurons: Vec<Neuron> = Vec::new();
         for _ in 1..=output_size {
-            neurons.push(Neuron::new(input_size, false));
+            neurons.push(Neuron::new(input_size, nonlin));
         }
         Layer { neurons}
     }
 
-    fn forward(&self, inputs: Vec<Value>, act: ActivationFunc) -> Vec<Value> {
+    pub fn forward(&self, inputs: Vec<Value>, act: ActivationFunc) -> Vec<Value> {
         let mut result: Vec<Value> = Vec::new();
         for neuron in self.neurons.iter() {
             result.push(neuron.forward(inputs.clone(), act.clone()));
@@ -162,7 +194,54 @@ impl Layer {
         result
     }
 
-    fn parameters(&self) -> Vec<Value> {
+    pub fn try_forward(&self, inputs: Vec<Value>, act: ActivationFunc) -> Result<Vec<Value>, BabygradError> {
+        self.neurons
+            .iter()
+            .map(|neuron| neuron.try_forward(inputs.clone(), act.clone()))
+            .collect()
+    }
+
+    /// The weights of the neurons as a `[inputs, outputs]` matrix, one
+    /// column per neuron. The elements are the weights themselves, so
+    /// backpropagating through the tensor fills in their gradients.
+    pub fn weights(&self) -> Tensor {
+        let inputs = self.neurons.first().map_or(0, |neuron| neuron.weights.len());
+        let data = (0..inputs)
+            .flat_map(|i| self.neurons.iter().map(move |neuron| neuron.weights[i].clone()))
+            .collect();
+        Tensor::from_values(data, &[inputs, self.neurons.len()]).unwrap()
+    }
+
+    /// The biases of the neurons, of shape `[outputs]`.
+    pub fn bias(&self) -> Tensor {
+        let data = self.neurons.iter().map(|neuron| neuron.bias.clone()).collect();
+        Tensor::from_values(data, &[self.neurons.len()]).unwrap()
+    }
+
+    /// `forward` on a tensor: `x.matmul(w) + b`, with `x` of shape
+    /// `[inputs]` or `[batch, inputs]`.
+    pub fn forward_tensor(&self, x: &Tensor, act: ActivationFunc) -> Result<Tensor, BabygradError> {
+        let output = &x.try_matmul(&self.weights())? + &self.bias();
+        if !self.neurons.iter().all(|neuron| neuron.nonlin) {
+            return Ok(output);
+        }
+        Ok(match act {
+            ActivationFunc::Tanh => output.tanh(),
+            ActivationFunc::Relu => output.relu(),
+            ActivationFunc::Sigmoid => output.sigmoid(),
+            ActivationFunc::Gelu => output.gelu(),
+            _ => output,
+        })
+    }
+
+    pub fn forward_tape<'t>(&self, tape: &'t Tape, inputs: &[Var<'t>], act: ActivationFunc) -> Vec<Var<'t>> {
+        self.neurons
+            .iter()
+            .map(|neuron| neuron.forward_tape(tape, inputs, act.clone()))
+            .collect()
+    }
+
+    pub fn parameters(&self) -> Vec<Value> {
         let mut parameters: Vec<Value> = Vec::new();
         for neuron in self.neurons.iter() {
             parameters.append(&mut neuron.parameters())
@@ -174,8 +253,15 @@ impl Layer {
 
 impl fmt::Display for Layer {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-       
-        todo!()
+        write!(f, "Layer of [")?;
+        for (i, neuron) in self.neurons.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            let kind = if neuron.nonlin { "Nonlinear" } else { "Linear" };
+            write!(f, "{}Neuron({})", kind, neuron.weights.len())?;
+        }
+        write!(f, "]")
     }
 }
 
@@ -200,6 +286,7 @@ class MLP(Module):
 */
 
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub struct MLP {
     pub layers: Vec<Layer>,
@@ -209,25 +296,70 @@ pub struct MLP {
 
 #[allow(dead_code)]
 impl MLP {
-    fn new(input_size: usize, hidden_layers_size: &[usize], act: ActivationFunc) -> MLP {
+    /// Panics on the configurations `try_new` rejects.
+    pub fn new(input_size: usize, hidden_layers_size: &[usize], act: ActivationFunc) -> MLP {
+        MLP::try_new(input_size, hidden_layers_size, act).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// An MLP with `input_size` inputs, one layer per entry of
+    /// `hidden_layers_size` applying `act`, and a linear single output.
+    /// Fails when there is no hidden layer or a size is 0.
+    pub fn try_new(input_size: usize, hidden_layers_size: &[usize], act: ActivationFunc) -> Result<MLP, BabygradError> {
+        if hidden_layers_size.is_empty() {
+            return Err(BabygradError::Model("no hidden layer".to_owned()));
+        }
+        if input_size == 0 || hidden_layers_size.contains(&0) {
+            return Err(BabygradError::Model("layers must have at least one input and one neuron".to_owned()));
+        }
         let mut layers: Vec<Layer> = Vec::new();
         let hlc = hidden_layers_size.len();
-        layers.push(Layer::new(input_size, hidden_layers_size[0]));
+        layers.push(Layer::with_nonlin(input_size, hidden_layers_size[0], true));
         for i in 0..hlc - 1 {
-            layers.push(Layer::new(hidden_layers_size[i], hidden_layers_size[i + 1]))
+            layers.push(Layer::with_nonlin(hidden_layers_size[i], hidden_layers_size[i + 1], true))
         }
         layers.push(Layer::new(hidden_layers_size[hlc - 1], 1));
-        MLP {layers, act}
+        Ok(MLP {layers, act})
     }
 
-    fn forward(&self, inputs: Vec<Value>) -> Value {
+    pub fn forward(&self, inputs: Vec<Value>) -> Value {
         let mut outputs: Vec<Value> = inputs;
         for layer in self.layers.iter() {
-            outputs = layer.forward(outputs, ActivationFunc::None)
+            outputs = layer.forward(outputs, self.act.clone())
         }
         assert!(outputs.len() == 1);
         outputs[0].clone()
     }
+
+    /// `forward` checking the number of inputs of every layer.
+    pub fn try_forward(&self, inputs: Vec<Value>) -> Result<Value, BabygradError> {
+        let mut outputs: Vec<Value> = inputs;
+        for layer in self.layers.iter() {
+            outputs = layer.try_forward(outputs, self.act.clone())?
+        }
+        match outputs.len() {
+            1 => Ok(outputs[0].clone()),
+            found => Err(BabygradError::Elements { expected: 1, found }),
+        }
+    }
+
+    /// `forward` on a tensor of shape `[inputs]` or `[batch, inputs]`, the
+    /// output has shape `[1]` or `[batch, 1]`.
+    pub fn forward_tensor(&self, x: &Tensor) -> Result<Tensor, BabygradError> {
+        let mut output = x.clone();
+        for layer in self.layers.iter() {
+            output = layer.forward_tensor(&output, self.act.clone())?;
+        }
+        Ok(output)
+    }
+
+    pub fn forward_tape<'t>(&self, tape: &'t Tape, inputs: &[Var<'t>]) -> Var<'t> {
+        let mut outputs: Vec<Var<'t>> = inputs.to_vec();
+        for layer in self.layers.iter() {
+            outputs = layer.forward_tape(tape, &outputs, self.act.clone())
+        }
+        assert!(outputs.len() == 1);
+        outputs[0]
+    }
 /*
     fn shape(&self) -> Vec<usize> {
         let mut sizes: Vec<usize> = Vec::new();
@@ -236,7 +368,7 @@ impl MLP {
         sizes
     }*/
 
-    fn parameters(&self) -> Vec<Value> {
+    pub fn parameters(&self) -> Vec<Value> {
         let mut parameters: Vec<Value> = Vec::new();
         for layer in self.layers.iter() {
             parameters.append(&mut layer.parameters())
@@ -244,14 +376,33 @@ impl MLP {
         parameters
     }
 
+    pub fn zero_grad(&self) {
+        for p in self.parameters() {
+            p.zero_gradient();
+        }
+    }
+
     
 }
 
 impl fmt::Display for MLP {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-       
-        todo!()
+        write!(f, "MLP of [")?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", layer)?;
+        }
+        write!(f, "]")
+    }
+}
+
+fn check_inputs(expected: usize, found: usize) -> Result<(), BabygradError> {
+    if expected != found {
+        return Err(BabygradError::Shape { expected, found });
     }
+    Ok(())
 }
 
 
@@ -260,8 +411,9 @@ impl fmt::Display for MLP {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::Op;
 
-    //#[test]
+    #[test]
     fn create_neutron() {
         let n = Neuron::new(3, false);        
         println!("{}", n);
@@ -269,18 +421,18 @@ mod tests {
 
     }
 
-    //#[test]
+    #[test]
     fn layer() {   
 
         let layer1 = Layer::new(4,4);
-        let mut inputs: Vec<Value> = Vec::new();
+        let inputs: Vec<Value> = Vec::new();
         layer1.forward(inputs, ActivationFunc::None);
         assert_eq!(layer1.parameters().len(), 20); 
     }
 
 
-    //#[test]
-    fn MLP() {       
+    #[test]
+    fn mlp() {       
         let xs: &[&[f64]] = &[
             &[1.0, 6.0, 0.0],
             &[0.0, 3.0, 1.0],
@@ -293,10 +445,193 @@ mod tests {
         let mlp = MLP::new(3, &[4, 4], ActivationFunc::None);
         assert_eq!(mlp.parameters().len(), 41); 
         let output = mlp.forward(Value::vec(xs[0]));
+        output.backward();
   
         assert_eq!(mlp.parameters().len(), 41);
     }
 
+    #[test]
+    fn neuron_activations() {
+        let mut n = Neuron::new(2, true);
+        n.weights = Value::vec(&[0.5, -1.0]);
+        let x = [2.0, 0.5];
+        let linear: f64 = 0.5 * 2.0 - 0.5;
+        let cases = [
+            (ActivationFunc::Tanh, linear.tanh()),
+            (ActivationFunc::Relu, linear),
+            (ActivationFunc::Sigmoid, 1.0 / (1.0 + (-linear).exp())),
+            (ActivationFunc::Gelu, Value::new(linear).gelu().value()),
+            (ActivationFunc::Linear, linear),
+        ];
+        for (act, expected) in cases {
+            let output = n.forward(Value::vec(&x), act.clone());
+            assert!((output.value() - expected).abs() < 1e-12);
+
+            let tape = Tape::new();
+            let tape_output = n.forward_tape(&tape, &tape.vars(&x), act);
+            assert!((tape_output.value() - expected).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn shape_errors() {
+        assert!(matches!(MLP::try_new(3, &[], ActivationFunc::None), Err(BabygradError::Model(_))));
+        assert!(matches!(MLP::try_new(3, &[4, 0], ActivationFunc::None), Err(BabygradError::Model(_))));
+
+        let mlp = MLP::try_new(3, &[4, 2], ActivationFunc::None).unwrap();
+        let x = [2.0, 3.0, -1.0];
+        assert_eq!(mlp.try_forward(Value::vec(&x)).unwrap().value(), mlp.forward(Value::vec(&x)).value());
+        let error = mlp.try_forward(Value::vec(&x[..2])).unwrap_err();
+        assert!(matches!(error, BabygradError::Shape { expected: 3, found: 2 }));
+        assert_eq!(error.to_string(), "expected 3 inputs, found 2");
+    }
+
+    #[test]
+    fn layer_as_matmul() {
+        let layer = Layer::with_nonlin(3, 2, true);
+        let x = [2.0, 3.0, -1.0];
+        let expected: Vec<f64> = layer
+            .forward(Value::vec(&x), ActivationFunc::Tanh)
+            .iter()
+            .map(Value::value)
+            .collect();
+        let output = layer.forward_tensor(&Tensor::new(&x, &[3]), ActivationFunc::Tanh).unwrap();
+        assert_eq!(output.shape(), &[2]);
+        for (a, b) in output.to_vec().iter().zip(expected) {
+            assert!((a - b).abs() < 1e-12);
+        }
+
+        // a batch of two samples, the gradients land in the neurons
+        let batch = Tensor::new(&[2.0, 3.0, -1.0, 0.0, 1.0, 0.5], &[2, 3]);
+        let output = layer.forward_tensor(&batch, ActivationFunc::Tanh).unwrap();
+        assert_eq!(output.shape(), &[2, 2]);
+        output.sum().backward();
+        assert!(layer.parameters().iter().all(|p| p.gradient() != 0.0));
+        assert!(layer.forward_tensor(&Tensor::zeros(&[2]), ActivationFunc::Tanh).is_err());
+    }
+
+    #[test]
+    fn mlp_on_tensors() {
+        let mlp = MLP::new(3, &[4, 4], ActivationFunc::None);
+        let xs = [[2.0, 3.0, -1.0], [1.0, 0.0, 0.5]];
+        let batch = Tensor::new(&xs.concat(), &[2, 3]);
+        let output = mlp.forward_tensor(&batch).unwrap();
+        assert_eq!(output.shape(), &[2, 1]);
+        for (x, y) in xs.iter().zip(output.to_vec()) {
+            assert!((mlp.forward(Value::vec(x)).value() - y).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn display() {
+        let mlp = MLP::new(2, &[3], ActivationFunc::None);
+        assert_eq!(mlp.layers[1].to_string(), "Layer of [LinearNeuron(3)]");
+        assert_eq!(
+            mlp.to_string(),
+            "MLP of [Layer of [NonlinearNeuron(2), NonlinearNeuron(2), NonlinearNeuron(2)], Layer of [LinearNeuron(3)]]"
+        );
+    }
+
+    #[test]
+    fn mlp_applies_activation() {
+        let mlp = MLP::new(3, &[4], ActivationFunc::Relu);
+        let x = [2.0, -3.0, 1.0];
+        let output = mlp.forward(Value::vec(&x));
+        output.backward();
+
+        let activations: Vec<Value> = output
+            .topological_sort()
+            .into_iter()
+            .filter(|node| matches!(node.op(), Op::Relu))
+            .collect();
+        assert_eq!(activations.len(), 4);
+        // the gradient of a hidden activation is its weight in the output neuron
+        let mut gradients: Vec<f64> = activations.iter().map(Value::gradient).collect();
+        let mut weights: Vec<f64> = mlp.layers[1].neurons[0].weights.iter().map(Value::value).collect();
+        gradients.sort_by(f64::total_cmp);
+        weights.sort_by(f64::total_cmp);
+        assert_eq!(gradients, weights);
+
+        // same network on the tape and on tensors
+        let tape = Tape::new();
+        let tape_output = mlp.forward_tape(&tape, &tape.vars(&x));
+        assert!((tape_output.value() - output.value()).abs() < 1e-12);
+        let tensor_output = mlp.forward_tensor(&Tensor::new(&x, &[3])).unwrap();
+        assert!((tensor_output.to_vec()[0] - output.value()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn mlp_inference_without_graph() {
+        let mlp = MLP::new(3, &[4, 4], ActivationFunc::None);
+        let x = [2.0, 3.0, -1.0];
+        let expected = mlp.forward(Value::vec(&x));
+        let output = crate::engine::no_grad(|| mlp.forward(Value::vec(&x)));
+        assert_eq!(output.value(), expected.value());
+        assert!(output.children().is_empty());
+    }
+
+    #[test]
+    fn mlp_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<MLP>();
+    }
+
+    #[test]
+    fn parallel_gradient_reduction() {
+        let xs: &[&[f64]] = &[
+            &[1.0, 6.0, 0.0],
+            &[0.0, 3.0, 1.0],
+            &[2.0, 4.0, 0.0],
+            &[0.0, 3.0, 2.0],
+        ];
+        let mlp = MLP::new(3, &[4, 4], ActivationFunc::None);
+
+        // sequential reference: sum of the per sample gradients
+        let mut expected = vec![0.0; mlp.parameters().len()];
+        for x in xs {
+            mlp.forward(Value::vec(x)).backward();
+            for (sum, p) in expected.iter_mut().zip(mlp.parameters()) {
+                *sum += p.gradient();
+            }
+        }
+
+        // one tape per thread, reduced into the shared parameters
+        mlp.zero_grad();
+        std::thread::scope(|scope| {
+            for x in xs {
+                let mlp = &mlp;
+                scope.spawn(move || {
+                    let tape = Tape::new();
+                    let output = mlp.forward_tape(&tape, &tape.vars(x));
+                    tape.backward_accumulate(output);
+                });
+            }
+        });
+
+        for (p, gradient) in mlp.parameters().iter().zip(expected) {
+            assert!((p.gradient() - gradient).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn mlp_on_tape() {
+        let mlp = MLP::new(3, &[4, 4], ActivationFunc::None);
+        let x = [2.0, 3.0, -1.0];
+
+        let output = mlp.forward(Value::vec(&x));
+        output.backward();
+        let expected: Vec<f64> = mlp.parameters().iter().map(Value::gradient).collect();
+
+        let tape = Tape::new();
+        let tape_output = mlp.forward_tape(&tape, &tape.vars(&x));
+        tape.backward(tape_output);
+
+        assert!((tape_output.value() - output.value()).abs() < 1e-12);
+        for (parameter, gradient) in mlp.parameters().iter().zip(expected) {
+            assert!((parameter.gradient() - gradient).abs() < 1e-12);
+        }
+    }
+
 
 
 }
\ No newline at end of file
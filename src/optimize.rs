@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::{Op, Value};
+
+/// Rewrites the graph of `root` into an equivalent, usually smaller one.
+///
+/// * nodes whose inputs are all constants are folded into a single constant,
+/// * structurally identical nodes (same op, same inputs) are merged, so a
+///   subexpression built twice is computed once.
+///
+/// The `Op::None` leaves are shared with the original graph, `backward` on
+/// the result writes the same gradients into them, and so are nodes with an
+/// op but no inputs (which `validate` rejects). The interior nodes are
+/// new: their hooks are not carried over, and a merged node keeps the label
+/// of the first one visited.
+pub fn optimize(root: &Value) -> Value {
+    let mut order = root.topological_sort();
+    order.reverse();
+
+    // original node -> rewritten node
+    let mut rewritten: HashMap<usize, Value> = HashMap::with_capacity(order.len());
+    // (op, rewritten inputs) -> rewritten node
+    let mut unique: HashMap<(String, Vec<usize>), Value> = HashMap::new();
+
+    for node in order.iter() {
+        let op = node.op();
+        let children: Vec<Value> = node
+            .children()
+            .iter()
+            .map(|child| rewritten[&child.key()].clone())
+            .collect();
+
+        let new = match op {
+            Op::None => node.clone(),
+            Op::Const => {
+                let key = (format!("const {}", node.value().to_bits()), vec![]);
+                unique.entry(key).or_insert_with(|| node.clone()).clone()
+            }
+            // malformed, kept as they are rather than evaluated
+            _ if children.is_empty() => node.clone(),
+            _ if children.iter().all(|child| matches!(child.op(), Op::Const)) => {
+                let inputs: Vec<f64> = children.iter().map(Value::value).collect();
+                let value = op.forward(&inputs);
+                let key = (format!("const {}", value.to_bits()), vec![]);
+                unique.entry(key).or_insert_with(|| Value::constant(value)).clone()
+            }
+            _ => {
+                let mut ids: Vec<usize> = children.iter().map(Value::key).collect();
+                if matches!(op, Op::Add | Op::Mul) {
+                    ids.sort_unstable();
+                }
+                let key = (op_key(&op), ids);
+                unique
+                    .entry(key)
+                    .or_insert_with(|| {
+                        let inputs: Vec<f64> = children.iter().map(Value::value).collect();
+                        Value::from_with_label(op.forward(&inputs), children, op, node.label())
+                    })
+                    .clone()
+            }
+        };
+        rewritten.insert(node.key(), new);
+    }
+
+    rewritten[&root.key()].clone()
+}
+
+fn op_key(op: &Op) -> String {
+    match op {
+        // two functions are the same op only if they are the same instance
+        Op::Custom(function) => format!("custom {:p}", Arc::as_ptr(function) as *const ()),
+        _ => format!("{:?}", op),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Function;
+
+    #[test]
+    fn folds_constants() {
+        let x = Value::new(1.5);
+        let c = (Value::constant(2.0) * 3.0).exp().log() + 1.0;
+        let out = x.clone() * c.clone();
+        let optimized = optimize(&out);
+
+        assert_eq!(optimized.topological_sort().len(), 3);
+        let folded = &optimized.children()[1];
+        assert!(matches!(folded.op(), Op::Const));
+        assert_approx!(folded.value(), 7.0);
+        assert_approx!(optimized.value(), out.value());
+    }
+
+    #[test]
+    fn keeps_ops_without_inputs() {
+        let x = Value::new(2.0);
+        let malformed = Value::from(1.0, vec![], Op::Tanh);
+        let optimized = optimize(&(malformed.clone() * x.clone()));
+        assert!(optimized.children()[0].ptr_eq(&malformed));
+        assert_eq!(optimized.value(), 2.0);
+        assert!(optimize(&malformed).ptr_eq(&malformed));
+    }
+
+    #[test]
+    fn merges_common_subexpressions() {
+        let a = Value::new(0.5);
+        let b = Value::new(-2.0);
+        // (a * b).tanh() is built twice, b * a once
+        let out = (a.clone() * b.clone()).tanh() + (a.clone() * b.clone()).tanh() * (b.clone() * a.clone());
+        out.backward();
+        let (da, db) = (a.gradient(), b.gradient());
+
+        let optimized = optimize(&out);
+        assert_eq!(out.topological_sort().len(), 9);
+        assert_eq!(optimized.topological_sort().len(), 6);
+        assert_approx!(optimized.value(), out.value());
+
+        optimized.backward();
+        assert_approx!(a.gradient(), da);
+        assert_approx!(b.gradient(), db);
+    }
+
+    #[test]
+    fn keeps_distinct_ops_apart() {
+        let a = Value::new(0.5);
+        let b = Value::new(2.0);
+        let out = a.clone().pow(2.0) + a.clone().pow(3.0) + (a.clone() - b.clone()) * (b.clone() - a.clone());
+        let optimized = optimize(&out);
+        assert_eq!(optimized.topological_sort().len(), out.topological_sort().len());
+        assert_approx!(optimized.value(), out.value());
+    }
+
+    #[derive(Debug)]
+    struct Square;
+
+    impl Function for Square {
+        fn forward(&self, inputs: &[f64]) -> f64 {
+            inputs[0] * inputs[0]
+        }
+
+        fn backward(&self, inputs: &[f64], _output: f64) -> Vec<f64> {
+            vec![2.0 * inputs[0]]
+        }
+
+        fn name(&self) -> String {
+            "square".to_owned()
+        }
+    }
+
+    #[test]
+    fn custom_functions() {
+        let square: Arc<dyn Function> = Arc::new(Square);
+        let x = Value::new(3.0);
+        let out = Value::apply(square.clone(), vec![x.clone()])
+            + Value::apply(square.clone(), vec![x.clone()])
+            + Value::apply(Arc::new(Square), vec![x.clone()])
+            + Value::apply(square, vec![Value::constant(4.0)]);
+        let optimized = optimize(&out);
+
+        // one application of each instance, the constant one folded
+        assert_eq!(optimized.topological_sort().len(), 7);
+        assert_approx!(optimized.value(), 9.0 + 9.0 + 9.0 + 16.0);
+        optimized.backward();
+        assert_approx!(x.gradient(), 18.0);
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::{Op, Value};
+
+/// A syntax or binding error, `column` is the 1-based position in the source
+/// (in characters) where it was found.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+impl Value {
+    /// Builds the graph of an arithmetic expression such as
+    /// `a*b + tanh(c) / 2`.
+    ///
+    /// The syntax has numbers, the variables of `bindings`, `+ - * / ^`,
+    /// parentheses and a function call for every `Op`: `tanh exp relu log
+    /// sqrt sin cos sigmoid softplus abs gelu` with one argument,
+    /// `leaky_relu(x, alpha)`, `pow max min atan2` with two. `x^n` with a
+    /// number `n` is `x.pow(n)`, any other exponent is `pow_value`. The
+    /// slope `alpha` of `leaky_relu` is not differentiated and must be a
+    /// number literal, possibly negated or parenthesized: `leaky_relu(x, 0.1)`.
+    ///
+    /// Parentheses, function calls, `-` and `^` nest at most `MAX_DEPTH`
+    /// levels deep, deeper input is an error rather than a stack overflow.
+    ///
+    /// Variables resolve to the `Value`s they are bound to, which get the
+    /// variable name as their label.
+    pub fn parse(src: &str, bindings: &HashMap<String, Value>) -> Result<Value, ParseError> {
+        let tokens = tokenize(src)?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            depth: 0,
+            bindings,
+        };
+        let root = parser.expression()?;
+        match parser.peek() {
+            (Token::End, _) => Ok(root),
+            (token, column) => Err(parser.error(column, format!("unexpected {}", token))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Symbol(char),
+    End,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "number `{}`", n),
+            Token::Ident(name) => write!(f, "`{}`", name),
+            Token::Symbol(c) => write!(f, "`{}`", c),
+            Token::End => write!(f, "end of input"),
+        }
+    }
+}
+
+fn tokenize(src: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut tokens: Vec<(Token, usize)> = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            // exponent: 1e-3, 2.5E4
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                i += 1;
+                if i < chars.len() && (chars[i] == '+' || chars[i] == '-') {
+                    i += 1;
+                }
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            let text: String = chars[start..i].iter().collect();
+            let number = text.parse::<f64>().map_err(|_| ParseError {
+                column,
+                message: format!("invalid number `{}`", text),
+            })?;
+            tokens.push((Token::Number(number), column));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push((Token::Ident(chars[start..i].iter().collect()), column));
+        } else if "+-*/^(),".contains(c) {
+            tokens.push((Token::Symbol(c), column));
+            i += 1;
+        } else {
+            return Err(ParseError {
+                column,
+                message: format!("unexpected character `{}`", c),
+            });
+        }
+    }
+    tokens.push((Token::End, chars.len() + 1));
+    Ok(tokens)
+}
+
+/// How deeply `Value::parse` lets an expression nest.
+pub const MAX_DEPTH: usize = 200;
+
+// Recursive descent, one method per precedence level:
+//   expression := term (("+" | "-") term)*
+//   term       := unary (("*" | "/") unary)*
+//   unary      := "-" unary | power
+//   power      := primary ("^" unary)?
+//   primary    := number | variable | function "(" arguments ")" | "(" expression ")"
+struct Parser<'b> {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    // nesting of `unary`, through which every nested subexpression goes
+    depth: usize,
+    bindings: &'b HashMap<String, Value>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> (Token, usize) {
+        self.tokens[self.position].clone()
+    }
+
+    fn next(&mut self) -> (Token, usize) {
+        let token = self.peek();
+        if token.0 != Token::End {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn eat(&mut self, symbol: char) -> bool {
+        if self.peek().0 == Token::Symbol(symbol) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), ParseError> {
+        match self.next() {
+            (Token::Symbol(c), _) if c == symbol => Ok(()),
+            (token, column) => Err(self.error(column, format!("expected `{}`, found {}", symbol, token))),
+        }
+    }
+
+    fn error(&self, column: usize, message: String) -> ParseError {
+        ParseError { column, message }
+    }
+
+    fn expression(&mut self) -> Result<Value, ParseError> {
+        let mut lhs = self.term()?;
+        loop {
+            if self.eat('+') {
+                lhs = lhs + self.term()?;
+            } else if self.eat('-') {
+                lhs = lhs - self.term()?;
+            } else {
+                return Ok(lhs);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<Value, ParseError> {
+        let mut lhs = self.unary()?;
+        loop {
+            if self.eat('*') {
+                lhs = lhs * self.unary()?;
+            } else if self.eat('/') {
+                lhs = lhs / self.unary()?;
+            } else {
+                return Ok(lhs);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<Value, ParseError> {
+        if self.depth == MAX_DEPTH {
+            let column = self.peek().1;
+            return Err(self.error(column, format!("expression nested deeper than {} levels", MAX_DEPTH)));
+        }
+        self.depth += 1;
+        let result = self.nested_unary();
+        self.depth -= 1;
+        result
+    }
+
+    fn nested_unary(&mut self) -> Result<Value, ParseError> {
+        if self.eat('-') {
+            let operand = self.unary()?;
+            // a negative literal stays a constant
+            return Ok(match operand.op() {
+                Op::Const => Value::constant(-operand.value()),
+                _ => -operand,
+            });
+        }
+        self.power()
+    }
+
+    fn power(&mut self) -> Result<Value, ParseError> {
+        let base = self.primary()?;
+        if self.eat('^') {
+            let exponent = self.unary()?;
+            return Ok(pow(base, exponent));
+        }
+        Ok(base)
+    }
+
+    fn primary(&mut self) -> Result<Value, ParseError> {
+        match self.next() {
+            (Token::Number(n), _) => Ok(Value::constant(n)),
+            (Token::Symbol('('), _) => {
+                let inner = self.expression()?;
+                self.expect(')')?;
+                Ok(inner)
+            }
+            (Token::Ident(name), column) if self.peek().0 == Token::Symbol('(') => {
+                self.position += 1;
+                let args = self.arguments()?;
+                self.call(&name, column, args)
+            }
+            (Token::Ident(name), column) => match self.bindings.get(&name) {
+                Some(value) => {
+                    value.set_label(&name);
+                    Ok(value.clone())
+                }
+                None => Err(self.error(column, format!("unknown variable `{}`", name))),
+            },
+            (token, column) => Err(self.error(column, format!("unexpected {}", token))),
+        }
+    }
+
+    // after the opening parenthesis, up to and including the closing one
+    fn arguments(&mut self) -> Result<Vec<Value>, ParseError> {
+        let mut args: Vec<Value> = Vec::new();
+        if self.eat(')') {
+            return Ok(args);
+        }
+        loop {
+            args.push(self.expression()?);
+            if self.eat(')') {
+                return Ok(args);
+            }
+            self.expect(',')?;
+        }
+    }
+
+    fn call(&self, name: &str, column: usize, args: Vec<Value>) -> Result<Value, ParseError> {
+        let arity = match name {
+            "tanh" | "exp" | "relu" | "log" | "sqrt" | "sin" | "cos" | "sigmoid" | "softplus" | "abs" | "gelu" => 1,
+            "leaky_relu" | "pow" | "max" | "min" | "atan2" => 2,
+            _ => return Err(self.error(column, format!("unknown function `{}`", name))),
+        };
+        if args.len() != arity {
+            let plural = if arity == 1 { "" } else { "s" };
+            return Err(self.error(
+                column,
+                format!("`{}` takes {} argument{}, got {}", name, arity, plural, args.len()),
+            ));
+        }
+
+        let mut args = args.into_iter();
+        let x = args.next().unwrap();
+        Ok(match name {
+            "tanh" => x.tanh(),
+            "exp" => x.exp(),
+            "relu" => x.relu(),
+            "log" => x.log(),
+            "sqrt" => x.sqrt(),
+            "sin" => x.sin(),
+            "cos" => x.cos(),
+            "sigmoid" => x.sigmoid(),
+            "softplus" => x.softplus(),
+            "abs" => x.abs(),
+            "gelu" => x.gelu(),
+            "leaky_relu" => {
+                let alpha = args.next().unwrap();
+                if !matches!(alpha.op(), Op::Const) {
+                    return Err(self.error(column, "the slope of `leaky_relu` must be a number".to_owned()));
+                }
+                x.leaky_relu(alpha.value())
+            }
+            "pow" => pow(x, args.next().unwrap()),
+            "max" => x.max(args.next().unwrap()),
+            "min" => x.min(args.next().unwrap()),
+            _ => x.atan2(args.next().unwrap()),
+        })
+    }
+}
+
+fn pow(base: Value, exponent: Value) -> Value {
+    match exponent.op() {
+        Op::Const => base.pow(exponent.value()),
+        _ => base.pow_value(exponent),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bindings(names: &[(&str, f64)]) -> HashMap<String, Value> {
+        names.iter().map(|(name, value)| (name.to_string(), Value::new(*value))).collect()
+    }
+
+    #[test]
+    fn parse_and_backward() {
+        let vars = bindings(&[("a", 2.0), ("b", -3.0), ("c", 0.5)]);
+        let root = Value::parse("a*b + tanh(c) / 2", &vars).unwrap();
+        assert_approx!(root.value(), -6.0 + 0.5_f64.tanh() / 2.0);
+
+        root.backward();
+        assert_approx!(vars["a"].gradient(), -3.0);
+        assert_approx!(vars["b"].gradient(), 2.0);
+        assert_approx!(vars["c"].gradient(), (1.0 - 0.5_f64.tanh().powi(2)) / 2.0);
+        assert_eq!(vars["a"].label().as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        let vars = bindings(&[("x", 3.0), ("y", 2.0)]);
+        let eval = |src: &str| Value::parse(src, &vars).unwrap().value();
+        assert_approx!(eval("1 - 2 - 3"), -4.0);
+        assert_approx!(eval("12 / 3 / 2"), 2.0);
+        assert_approx!(eval("-x^2"), -9.0);
+        assert_approx!(eval("2^3^2"), 512.0);
+        assert_approx!(eval("x^-1"), 1.0 / 3.0);
+        assert_approx!(eval("(x + y) * 2e-1"), 1.0);
+        assert_approx!(eval("max(x, y) - min(x, -y) * atan2(y, x)"), 3.0 + 2.0 * 2.0_f64.atan2(3.0));
+
+        assert!(matches!(Value::parse("x^2", &vars).unwrap().op(), Op::Pow(e) if e == 2.0));
+        assert!(matches!(Value::parse("x^y", &vars).unwrap().op(), Op::PowValue));
+        assert!(matches!(Value::parse("pow(x, y)", &vars).unwrap().op(), Op::PowValue));
+    }
+
+    type UnaryOp = fn(Value) -> Value;
+
+    #[test]
+    fn every_function() {
+        let vars = bindings(&[("x", -0.7)]);
+        let functions: Vec<(&str, UnaryOp)> = vec![
+            ("tanh", Value::tanh), ("exp", Value::exp), ("relu", Value::relu), ("log", Value::log),
+            ("sqrt", Value::sqrt), ("sin", Value::sin), ("cos", Value::cos), ("sigmoid", Value::sigmoid),
+            ("softplus", Value::softplus), ("abs", Value::abs), ("gelu", Value::gelu),
+            ("leaky_relu", |x| x.leaky_relu(0.1)),
+        ];
+        for (name, f) in functions {
+            let src = if name == "leaky_relu" { "leaky_relu(-x, 0.1)".to_owned() } else { format!("{}(-x)", name) };
+            let parsed = Value::parse(&src, &vars).unwrap();
+            assert_approx!(parsed.value(), f(Value::new(0.7)).value());
+        }
+    }
+
+    #[test]
+    fn errors_have_columns() {
+        let vars = bindings(&[("x", 1.0)]);
+        let error = |src: &str| Value::parse(src, &vars).unwrap_err().to_string();
+        assert_eq!(error("x + y"), "column 5: unknown variable `y`");
+        assert_eq!(error("x * (2 + x"), "column 11: expected `)`, found end of input");
+        assert_eq!(error("x $ 2"), "column 3: unexpected character `$`");
+        assert_eq!(error("2 + tanh(x, x)"), "column 5: `tanh` takes 1 argument, got 2");
+        assert_eq!(error("foo(x)"), "column 1: unknown function `foo`");
+        assert_eq!(error("x 2"), "column 3: unexpected number `2`");
+        assert_eq!(error("x * * 2"), "column 5: unexpected `*`");
+        assert_eq!(error("1.2.3"), "column 1: invalid number `1.2.3`");
+        assert_eq!(error("leaky_relu(x, x)"), "column 1: the slope of `leaky_relu` must be a number");
+    }
+
+    #[test]
+    fn nesting_depth() {
+        let vars = bindings(&[("x", 1.0)]);
+        let nested = |depth: usize| format!("{}x{}", "(".repeat(depth), ")".repeat(depth));
+        assert_approx!(Value::parse(&nested(MAX_DEPTH - 1), &vars).unwrap().value(), 1.0);
+
+        let error = Value::parse(&nested(100_000), &vars).unwrap_err();
+        assert_eq!(error.column, MAX_DEPTH + 1);
+        assert_eq!(error.message, format!("expression nested deeper than {} levels", MAX_DEPTH));
+        assert!(Value::parse(&"-".repeat(100_000), &vars).is_err());
+        assert!(Value::parse(&"tanh(".repeat(100_000), &vars).is_err());
+        assert!(Value::parse(&"x^".repeat(100_000), &vars).is_err());
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+
+use crate::{Op, Value};
+
+/// One step of a `Program`: computes slot `i` (the instruction's position)
+/// from the slots in `args[args_start..args_start + args_len]`.
+struct Instruction {
+    op: Op,
+    args_start: usize,
+    args_len: usize,
+}
+
+/// A `Value` graph traced once into a flat list of instructions.
+///
+/// The program can be re-run with new leaf values, forward and backward,
+/// without building a new graph: the values and gradients live in buffers
+/// allocated at compile time.
+pub struct Program {
+    instructions: Vec<Instruction>,
+    args: Vec<usize>,
+    values: Vec<f64>,
+    gradients: Vec<f64>,
+    // the `Op::None` leaves of the traced graph and their slots
+    leaves: Vec<Value>,
+    leaf_slots: Vec<usize>,
+    leaf_gradients: Vec<f64>,
+}
+
+/// The result of `Program::run`.
+#[derive(Debug)]
+pub struct Evaluation<'p> {
+    pub output: f64,
+    /// Gradient of the output for every leaf, in the order of `Program::leaves`.
+    pub gradients: &'p [f64],
+}
+
+impl Program {
+    /// Traces the graph of `root`. Every node becomes one instruction in
+    /// topological order, the root being the last one. Constants are baked
+    /// into the program, the `Op::None` leaves become its inputs.
+    pub fn compile(root: &Value) -> Program {
+        let mut order = root.topological_sort();
+        order.reverse();
+
+        let slots: HashMap<usize, usize> = order
+            .iter()
+            .enumerate()
+            .map(|(slot, node)| (node.key(), slot))
+            .collect();
+
+        let mut instructions: Vec<Instruction> = Vec::with_capacity(order.len());
+        let mut args: Vec<usize> = Vec::new();
+        let mut values: Vec<f64> = Vec::with_capacity(order.len());
+        let mut leaves: Vec<Value> = Vec::new();
+        let mut leaf_slots: Vec<usize> = Vec::new();
+        for (slot, node) in order.iter().enumerate() {
+            let children = node.children();
+            let op = node.op();
+            let args_start = args.len();
+            args.extend(children.iter().map(|child| slots[&child.key()]));
+            if matches!(op, Op::None) {
+                leaves.push(node.clone());
+                leaf_slots.push(slot);
+            }
+            instructions.push(Instruction {
+                op,
+                args_start,
+                args_len: children.len(),
+            });
+            values.push(node.value());
+        }
+
+        Program {
+            gradients: vec![0.0; instructions.len()],
+            leaf_gradients: vec![0.0; leaves.len()],
+            instructions,
+            args,
+            values,
+            leaves,
+            leaf_slots,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    /// The inputs of the program, in the order `run` and `forward` expect them.
+    pub fn leaves(&self) -> &[Value] {
+        &self.leaves
+    }
+
+    pub fn leaf_index(&self, leaf: &Value) -> Option<usize> {
+        self.leaves.iter().position(|l| l.ptr_eq(leaf))
+    }
+
+    /// Changes the value of one leaf for the next `evaluate`, e.g. a single
+    /// parameter, without passing all of them again.
+    pub fn set_leaf(&mut self, index: usize, value: f64) {
+        self.values[self.leaf_slots[index]] = value;
+    }
+
+    /// Evaluates the program with new leaf values and returns the output.
+    pub fn forward(&mut self, leaves: &[f64]) -> f64 {
+        assert_eq!(leaves.len(), self.leaves.len(), "one value per leaf of the program");
+        for (slot, value) in self.leaf_slots.iter().zip(leaves.iter()) {
+            self.values[*slot] = *value;
+        }
+        self.evaluate()
+    }
+
+    /// Evaluates the program with the leaf values it holds: those of the
+    /// compiled graph, of the last `forward`, or given to `set_leaf`.
+    pub fn evaluate(&mut self) -> f64 {
+        let mut inputs: Vec<f64> = Vec::new();
+        for (slot, instruction) in self.instructions.iter().enumerate() {
+            if instruction.args_len == 0 {
+                continue;
+            }
+            let args = &self.args[instruction.args_start..instruction.args_start + instruction.args_len];
+            inputs.clear();
+            inputs.extend(args.iter().map(|arg| self.values[*arg]));
+            self.values[slot] = instruction.op.forward(&inputs);
+        }
+        self.output()
+    }
+
+    /// Backpropagates through the last `forward` and returns the gradients of
+    /// the leaves.
+    pub fn backward(&mut self) -> &[f64] {
+        self.gradients.iter_mut().for_each(|g| *g = 0.0);
+        if let Some(root) = self.gradients.last_mut() {
+            *root = 1.0;
+        }
+
+        let mut inputs: Vec<f64> = Vec::new();
+        let mut locals: Vec<f64> = Vec::new();
+        for (slot, instruction) in self.instructions.iter().enumerate().rev() {
+            if instruction.args_len == 0 {
+                continue;
+            }
+            let args = &self.args[instruction.args_start..instruction.args_start + instruction.args_len];
+            inputs.clear();
+            inputs.extend(args.iter().map(|arg| self.values[*arg]));
+            locals.clear();
+            locals.resize(args.len(), 0.0);
+            instruction.op.local_gradients(&inputs, self.values[slot], &mut locals);
+            let out_gradient = self.gradients[slot];
+            for (arg, local) in args.iter().zip(locals.iter()) {
+                self.gradients[*arg] += local * out_gradient;
+            }
+        }
+
+        for (gradient, slot) in self.leaf_gradients.iter_mut().zip(self.leaf_slots.iter()) {
+            *gradient = self.gradients[*slot];
+        }
+        &self.leaf_gradients
+    }
+
+    /// Runs `forward` then `backward` with new leaf values.
+    pub fn run(&mut self, leaves: &[f64]) -> Evaluation<'_> {
+        let output = self.forward(leaves);
+        Evaluation {
+            output,
+            gradients: self.backward(),
+        }
+    }
+
+    pub fn output(&self) -> f64 {
+        self.values.last().copied().unwrap_or(0.0)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nn::{ActivationFunc, MLP};
+
+    #[test]
+    fn rerun_with_new_leaves() {
+        let a = Value::new(2.0);
+        let b = Value::new(-3.0);
+        let c = (a.clone() * b.clone() + 1.0).tanh() + b.clone().pow(2.0) / a.clone();
+
+        let mut program = Program::compile(&c);
+        assert_eq!(program.len(), 9);
+        assert_eq!(program.leaves().len(), 2);
+        let ia = program.leaf_index(&a).unwrap();
+        let ib = program.leaf_index(&b).unwrap();
+
+        let mut leaves = vec![0.0; 2];
+        for (x, y) in [(2.0, -3.0), (0.5, 1.5), (-1.0, 0.25)] {
+            leaves[ia] = x;
+            leaves[ib] = y;
+            let evaluation = program.run(&leaves);
+
+            let a = Value::new(x);
+            let b = Value::new(y);
+            let c = (a.clone() * b.clone() + 1.0).tanh() + b.clone().pow(2.0) / a.clone();
+            c.backward();
+            assert_approx!(evaluation.output, c.value());
+            assert_approx!(evaluation.gradients[ia], a.gradient());
+            assert_approx!(evaluation.gradients[ib], b.gradient());
+        }
+    }
+
+    #[test]
+    fn set_single_leaves() {
+        let a = Value::new(2.0);
+        let b = Value::new(-3.0);
+        let c = a.clone() * b.clone() + a.clone();
+
+        let mut program = Program::compile(&c);
+        let (ia, ib) = (program.leaf_index(&a).unwrap(), program.leaf_index(&b).unwrap());
+        assert_approx!(program.evaluate(), -4.0);
+        program.set_leaf(ib, 5.0);
+        assert_approx!(program.evaluate(), 12.0);
+        assert_approx!(program.backward()[ia], 6.0);
+        // `forward` replaces them all
+        assert_approx!(program.forward(&[1.0, 1.0]), 2.0);
+    }
+
+    #[test]
+    fn compiled_mlp() {
+        let mlp = MLP::new(3, &[4, 4], ActivationFunc::None);
+        let inputs = Value::vec(&[1.0, 6.0, 0.0]);
+        let mut program = Program::compile(&mlp.forward(inputs.clone()));
+        assert_eq!(program.leaves().len(), 41 + 3);
+
+        let mut leaves: Vec<f64> = program.leaves().iter().map(Value::value).collect();
+        let x = [0.0, 3.0, 2.0];
+        for (input, value) in inputs.iter().zip(x) {
+            leaves[program.leaf_index(input).unwrap()] = value;
+        }
+        let output = program.forward(&leaves);
+
+        let expected = mlp.forward(Value::vec(&x));
+        expected.backward();
+        assert_approx!(output, expected.value());
+
+        let gradients = program.backward().to_vec();
+        for p in mlp.parameters() {
+            assert_approx!(gradients[program.leaf_index(&p).unwrap()], p.gradient());
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::{Op, Value};
+
+/// Thresholds used by `Value::stats_with`.
+#[derive(Clone, Debug)]
+pub struct StatsOptions {
+    /// A node with more parents or children than this is reported in
+    /// `GraphStats::fan_out`.
+    pub fan_out_threshold: usize,
+}
+
+impl Default for StatsOptions {
+    fn default() -> Self {
+        StatsOptions { fan_out_threshold: 1000 }
+    }
+}
+
+/// A node with a pathological number of edges, e.g. an input feeding every
+/// neuron of a wide layer or a custom op over thousands of inputs.
+#[derive(Clone, Debug)]
+pub struct FanOut {
+    /// Position of the node with its children first, as in `to_json` and
+    /// `to_graph`: the root is last.
+    pub position: usize,
+    pub label: Option<String>,
+    pub op: &'static str,
+    /// Number of times the node is used as an input.
+    pub parents: usize,
+    pub children: usize,
+}
+
+/// Size and shape of the graph reachable from a root `Value`.
+#[derive(Clone, Debug)]
+pub struct GraphStats {
+    /// Distinct nodes, a shared node counts once.
+    pub nodes: usize,
+    /// Nodes used as an input more than once.
+    pub shared: usize,
+    /// Input references, a shared node counts once per use.
+    pub edges: usize,
+    /// Longest path from the root to a leaf, in edges.
+    pub depth: usize,
+    /// `Op::None` leaves.
+    pub leaves: usize,
+    /// `Op::Const` leaves.
+    pub constants: usize,
+    /// Node count per `Op::name`.
+    pub ops: BTreeMap<&'static str, usize>,
+    /// Approximate heap memory of the nodes: allocations, children lists,
+    /// labels and hooks (not what custom functions or hooks capture).
+    pub heap_bytes: usize,
+    pub max_parents: usize,
+    pub max_children: usize,
+    /// The nodes above `StatsOptions::fan_out_threshold`.
+    pub fan_out: Vec<FanOut>,
+}
+
+impl Value {
+    /// `stats_with` the default options.
+    pub fn stats(&self) -> GraphStats {
+        self.stats_with(&StatsOptions::default())
+    }
+
+    /// Walks the graph of `self` once and reports its size and shape.
+    pub fn stats_with(&self, options: &StatsOptions) -> GraphStats {
+        let order = self.topological_sort();
+
+        let mut parents: HashMap<usize, usize> = HashMap::with_capacity(order.len());
+        for node in order.iter() {
+            for child in node.children().iter() {
+                *parents.entry(child.key()).or_insert(0) += 1;
+            }
+        }
+
+        let mut stats = GraphStats {
+            nodes: order.len(),
+            shared: 0,
+            edges: 0,
+            depth: 0,
+            leaves: 0,
+            constants: 0,
+            ops: BTreeMap::new(),
+            heap_bytes: 0,
+            max_parents: 0,
+            max_children: 0,
+            fan_out: Vec::new(),
+        };
+        // children come before their parents in reverse topological order
+        let mut depths: HashMap<usize, usize> = HashMap::with_capacity(order.len());
+        for (position, node) in order.iter().rev().enumerate() {
+            let op = node.op();
+            let children = node.children();
+            let node_parents = parents.get(&node.key()).copied().unwrap_or(0);
+            let depth = children.iter().map(|child| depths[&child.key()] + 1).max().unwrap_or(0);
+            depths.insert(node.key(), depth);
+
+            stats.edges += children.len();
+            stats.shared += usize::from(node_parents > 1);
+            stats.leaves += usize::from(matches!(op, Op::None));
+            stats.constants += usize::from(matches!(op, Op::Const));
+            *stats.ops.entry(op.name()).or_insert(0) += 1;
+            stats.heap_bytes += node.heap_bytes();
+            stats.max_parents = stats.max_parents.max(node_parents);
+            stats.max_children = stats.max_children.max(children.len());
+            if node_parents > options.fan_out_threshold || children.len() > options.fan_out_threshold {
+                stats.fan_out.push(FanOut {
+                    position,
+                    label: node.label(),
+                    op: op.name(),
+                    parents: node_parents,
+                    children: children.len(),
+                });
+            }
+        }
+        stats.depth = depths[&self.key()];
+        stats
+    }
+}
+
+impl fmt::Display for GraphStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} nodes ({} shared), {} edges, depth {}, {} leaves, {} constants, ~{} heap bytes",
+            self.nodes, self.shared, self.edges, self.depth, self.leaves, self.constants, self.heap_bytes
+        )?;
+        let ops: Vec<String> = self.ops.iter().map(|(op, count)| format!("{} {}", op, count)).collect();
+        writeln!(f, "  ops: {}", ops.join(", "))?;
+        for node in self.fan_out.iter() {
+            writeln!(
+                f,
+                "  warning: node {} ({}, {}) has {} parents and {} children",
+                node.position,
+                node.label.as_deref().unwrap_or("unlabelled"),
+                node.op,
+                node.parents,
+                node.children
+            )?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::leaf;
+    use crate::nn::{ActivationFunc, MLP};
+    use crate::Function;
+    use std::sync::Arc;
+
+    #[test]
+    fn small_graph() {
+        let x = Value::new(2.0);
+        let y = Value::new(3.0);
+        let s = x.clone() + y.clone();
+        let out = (s.clone() * x.clone()).tanh() + s.clone() * 2.0;
+        let stats = out.stats();
+
+        // x, y, s, s * x, tanh, 2, s * 2, out
+        assert_eq!(stats.nodes, 8);
+        assert_eq!(stats.shared, 2);
+        assert_eq!(stats.edges, 9);
+        assert_eq!(stats.depth, 4);
+        assert_eq!(stats.leaves, 2);
+        assert_eq!(stats.constants, 1);
+        assert_eq!(stats.ops["add"], 2);
+        assert_eq!(stats.ops["mul"], 2);
+        assert_eq!(stats.ops["tanh"], 1);
+        assert_eq!(stats.max_parents, 2);
+        assert_eq!(stats.max_children, 2);
+        assert!(stats.heap_bytes >= 8 * std::mem::size_of::<Value>());
+        assert!(stats.fan_out.is_empty());
+        assert!(stats.to_string().starts_with("8 nodes (2 shared), 9 edges, depth 4"));
+    }
+
+    #[test]
+    fn mlp_graph() {
+        let mlp = MLP::new(3, &[4, 4, 1], ActivationFunc::Tanh);
+        let out = mlp.forward(Value::vec(&[1.0, -2.0, 0.5]));
+        let stats = out.stats();
+        assert_eq!(stats.leaves, 3 + mlp.parameters().len());
+        // one product and one sum per weight, and the activations of the
+        // 4 + 4 + 1 hidden neurons
+        assert_eq!(stats.ops["mul"], stats.ops["add"]);
+        assert_eq!(stats.ops["tanh"], 9);
+        assert_eq!(stats.nodes, stats.leaves + 2 * stats.ops["mul"] + 9);
+        // every input feeds the 4 neurons of the first layer
+        assert_eq!(stats.max_parents, 4);
+    }
+
+    #[derive(Debug)]
+    struct Sum;
+
+    impl Function for Sum {
+        fn forward(&self, inputs: &[f64]) -> f64 {
+            inputs.iter().sum()
+        }
+
+        fn backward(&self, inputs: &[f64], _output: f64) -> Vec<f64> {
+            vec![1.0; inputs.len()]
+        }
+
+        fn name(&self) -> String {
+            "sum".to_owned()
+        }
+    }
+
+    #[test]
+    fn fan_out_warnings() {
+        let x = leaf(1.0, "x");
+        let inputs: Vec<Value> = (0..20).map(|i| x.clone() * i as f64).collect();
+        let out = Value::apply(Arc::new(Sum), inputs);
+
+        let stats = out.stats_with(&StatsOptions { fan_out_threshold: 10 });
+        assert_eq!(stats.fan_out.len(), 2);
+        assert_eq!(stats.max_children, 20);
+        assert_eq!(stats.max_parents, 20);
+        let warning = stats.fan_out.iter().find(|node| node.label.is_some()).unwrap();
+        assert_eq!(warning.parents, 20);
+        let mut order = out.topological_sort();
+        order.reverse();
+        let position = order.iter().position(|node| node.ptr_eq(&x)).unwrap();
+        assert_eq!(warning.position, position);
+        // the custom op is the root
+        assert_eq!(stats.fan_out.iter().find(|node| node.label.is_none()).unwrap().position, stats.nodes - 1);
+        assert!(stats.to_string().contains(&format!("warning: node {} (x, none) has 20 parents", position)));
+        assert!(out.stats().fan_out.is_empty());
+    }
+}
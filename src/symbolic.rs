@@ -0,0 +1,702 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops;
+
+use crate::{Op, Value};
+
+/// A symbolic expression traced from a `Value` graph.
+///
+/// `Display` prints it as an infix expression (`(1 - tanh(x * w)^2) * w`),
+/// `to_latex` as LaTeX. The arithmetic operators simplify as they build:
+/// `x * 1`, `x + 0`, `x^1` and operations on numbers are folded away.
+///
+/// A node used more than once in the graph is traced once and named, by its
+/// label or `t{position}`, its derivative `d{name}`. A name already taken by
+/// a leaf or another shared node falls back to `t{position}`, with a suffix
+/// if needed. The names used more than once in the result are defined by a
+/// `Let`: `t2 * t2 where t2 = x + y`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Num(f64),
+    /// A leaf, named by its label or `v{id}`, or a name defined by a `Let`
+    /// for a shared subexpression or its derivative.
+    Sym(String),
+    Neg(Box<Expr>),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+    Div(Box<Expr>, Box<Expr>),
+    Pow(Box<Expr>, Box<Expr>),
+    /// A named function: the unary ops, `max`, `atan2`, custom functions,
+    /// and the derivatives without a closed form: `step(x)` (1 for x > 0),
+    /// `sign`, `gelu'`, and `ge(a, b)` / `le(a, b)` (1 when a >= b, a <= b).
+    Call(String, Vec<Expr>),
+    /// The body, with the shared subexpressions defined in order.
+    Let(Vec<(String, Expr)>, Box<Expr>),
+}
+
+/// The expression computed by `root`, leaves included.
+pub fn expression(root: &Value) -> Expr {
+    trace(root, None).0
+}
+
+/// The symbolic derivative of `root` with respect to the leaf `wrt`.
+/// It is `0` when `wrt` is not in the graph.
+pub fn derivative(root: &Value, wrt: &Value) -> Expr {
+    trace(root, Some(wrt)).1
+}
+
+// Forward mode over the graph: every node gets its expression and the
+// derivative of that expression, memoized so shared nodes are traced once.
+fn trace(root: &Value, wrt: Option<&Value>) -> (Expr, Expr) {
+    let mut order = root.topological_sort();
+    order.reverse();
+
+    let mut parents: HashMap<usize, usize> = HashMap::with_capacity(order.len());
+    for node in order.iter() {
+        for child in node.children().iter() {
+            *parents.entry(child.key()).or_insert(0) += 1;
+        }
+    }
+
+    // the leaf names, and the names given to shared nodes so far
+    let mut names: HashSet<String> = order
+        .iter()
+        .filter(|node| matches!(node.op(), Op::None))
+        .map(leaf_name)
+        .collect();
+    let mut bindings: Vec<(String, Expr)> = Vec::new();
+    let mut traced: HashMap<usize, (Expr, Expr)> = HashMap::with_capacity(order.len());
+    for (position, node) in order.iter().enumerate() {
+        let children = node.children();
+        let (a, da): (Vec<Expr>, Vec<Expr>) = children.iter().map(|child| traced[&child.key()].clone()).unzip();
+        let unary = |name: &str| Expr::call(name, vec![a[0].clone()]);
+
+        let (e, d) = match node.op() {
+            Op::None => {
+                let e = Expr::Sym(leaf_name(node));
+                let d = match wrt {
+                    Some(wrt) if wrt.ptr_eq(node) => Expr::Num(1.0),
+                    _ => Expr::Num(0.0),
+                };
+                (e, d)
+            }
+            Op::Const => (Expr::Num(node.value()), Expr::Num(0.0)),
+            Op::Add => (a[0].clone() + a[1].clone(), da[0].clone() + da[1].clone()),
+            Op::Sub => (a[0].clone() - a[1].clone(), da[0].clone() - da[1].clone()),
+            Op::Mul => (
+                a[0].clone() * a[1].clone(),
+                da[0].clone() * a[1].clone() + a[0].clone() * da[1].clone(),
+            ),
+            Op::Div => (
+                a[0].clone() / a[1].clone(),
+                da[0].clone() / a[1].clone() - a[0].clone() * da[1].clone() / a[1].clone().pow(Expr::Num(2.0)),
+            ),
+            Op::Neg => (-a[0].clone(), -da[0].clone()),
+            Op::Tanh => {
+                let e = unary("tanh");
+                let d = (Expr::Num(1.0) - e.clone().pow(Expr::Num(2.0))) * da[0].clone();
+                (e, d)
+            }
+            Op::Exp => {
+                let e = unary("exp");
+                (e.clone(), e * da[0].clone())
+            }
+            Op::Pow(n) => (
+                a[0].clone().pow(Expr::Num(n)),
+                Expr::Num(n) * a[0].clone().pow(Expr::Num(n - 1.0)) * da[0].clone(),
+            ),
+            Op::Relu => (unary("relu"), unary("step") * da[0].clone()),
+            Op::Log => (unary("log"), da[0].clone() / a[0].clone()),
+            Op::Sqrt => {
+                let e = unary("sqrt");
+                (e.clone(), da[0].clone() / (Expr::Num(2.0) * e))
+            }
+            Op::Sin => (unary("sin"), unary("cos") * da[0].clone()),
+            Op::Cos => (unary("cos"), -(unary("sin") * da[0].clone())),
+            Op::Sigmoid => {
+                let e = unary("sigmoid");
+                (e.clone(), e.clone() * (Expr::Num(1.0) - e) * da[0].clone())
+            }
+            Op::Softplus => (unary("softplus"), unary("sigmoid") * da[0].clone()),
+            Op::Abs => (unary("abs"), unary("sign") * da[0].clone()),
+            Op::LeakyRelu(alpha) => (
+                Expr::call("leaky_relu", vec![a[0].clone(), Expr::Num(alpha)]),
+                (Expr::Num(alpha) + Expr::Num(1.0 - alpha) * unary("step")) * da[0].clone(),
+            ),
+            Op::Gelu => (unary("gelu"), unary("gelu'") * da[0].clone()),
+            Op::PowValue => {
+                let e = a[0].clone().pow(a[1].clone());
+                let d = a[1].clone() * a[0].clone().pow(a[1].clone() - Expr::Num(1.0)) * da[0].clone()
+                    + e.clone() * unary("log") * da[1].clone();
+                (e, d)
+            }
+            Op::Max | Op::Min => {
+                // like `backward`, a tie selects the lhs
+                let (name, selected) = match node.op() {
+                    Op::Max => ("max", Expr::call("ge", a.clone())),
+                    _ => ("min", Expr::call("le", a.clone())),
+                };
+                (
+                    Expr::call(name, a.clone()),
+                    selected.clone() * da[0].clone() + (Expr::Num(1.0) - selected) * da[1].clone(),
+                )
+            }
+            Op::Atan2 => {
+                let (y, x) = (a[0].clone(), a[1].clone());
+                let r2 = x.clone().pow(Expr::Num(2.0)) + y.clone().pow(Expr::Num(2.0));
+                (
+                    Expr::call("atan2", a.clone()),
+                    (x * da[0].clone() - y * da[1].clone()) / r2,
+                )
+            }
+            Op::Custom(function) => {
+                let name = function.name();
+                let d = da.iter().enumerate().fold(Expr::Num(0.0), |sum, (i, d)| {
+                    // name' for one input, name'i for the partial derivatives
+                    let partial = if a.len() == 1 { format!("{}'", name) } else { format!("{}'{}", name, i) };
+                    sum + Expr::call(&partial, a.clone()) * d.clone()
+                });
+                (Expr::call(&name, a.clone()), d)
+            }
+        };
+        let shared = parents.get(&node.key()).copied().unwrap_or(0) > 1;
+        let (e, d) = if shared && !matches!(node.op(), Op::None | Op::Const) {
+            let name = fresh_name(&mut names, node.label(), position);
+            let d = bind(&mut bindings, format!("d{}", name), d);
+            (bind(&mut bindings, name, e), d)
+        } else {
+            (e, d)
+        };
+        traced.insert(node.key(), (e, d));
+    }
+
+    let (e, d) = traced.remove(&root.key()).unwrap();
+    (with_bindings(e, &bindings), with_bindings(d, &bindings))
+}
+
+fn leaf_name(node: &Value) -> String {
+    node.label().unwrap_or_else(|| format!("v{}", node.id()))
+}
+
+// `preferred` unless it or its derivative's name is taken, else `t{position}`
+// with the first free suffix; reserves both names
+fn fresh_name(names: &mut HashSet<String>, preferred: Option<String>, position: usize) -> String {
+    let free = |name: &String| !names.contains(name) && !names.contains(&format!("d{}", name));
+    let name = match preferred.filter(free) {
+        Some(name) => name,
+        None => {
+            let base = format!("t{}", position);
+            std::iter::once(base.clone())
+                .chain((1..).map(|i| format!("{}_{}", base, i)))
+                .find(free)
+                .unwrap()
+        }
+    };
+    names.insert(format!("d{}", name));
+    names.insert(name.clone());
+    name
+}
+
+// names `e` unless it is already a number or a name
+fn bind(bindings: &mut Vec<(String, Expr)>, name: String, e: Expr) -> Expr {
+    match e {
+        Expr::Num(_) | Expr::Sym(_) => e,
+        e => {
+            bindings.push((name.clone(), e));
+            Expr::Sym(name)
+        }
+    }
+}
+
+// `body` with the definitions of the names it uses, the names used once
+// are replaced by their expression
+fn with_bindings(mut body: Expr, bindings: &[(String, Expr)]) -> Expr {
+    let mut uses: HashMap<String, usize> = HashMap::new();
+    body.count_symbols(&mut uses);
+    // a binding only refers to the earlier ones
+    let mut kept: Vec<(String, Expr)> = Vec::new();
+    for (name, e) in bindings.iter().rev() {
+        if uses.get(name).copied().unwrap_or(0) > 0 {
+            e.count_symbols(&mut uses);
+            kept.push((name.clone(), e.clone()));
+        }
+    }
+    kept.reverse();
+
+    let mut i = kept.len();
+    while i > 0 {
+        i -= 1;
+        if uses[&kept[i].0] > 1 {
+            continue;
+        }
+        let (name, e) = kept.remove(i);
+        body = body.substitute(&name, &e);
+        for (_, later) in kept[i..].iter_mut() {
+            *later = later.substitute(&name, &e);
+        }
+    }
+    if kept.is_empty() {
+        body
+    } else {
+        Expr::Let(kept, Box::new(body))
+    }
+}
+
+impl Expr {
+    pub fn call(name: &str, args: Vec<Expr>) -> Expr {
+        Expr::Call(name.to_owned(), args)
+    }
+
+    pub fn pow(self, exponent: Expr) -> Expr {
+        match (self, exponent) {
+            (base, e) if e.is_num(1.0) => base,
+            (_, e) if e.is_num(0.0) => Expr::Num(1.0),
+            (Expr::Num(b), Expr::Num(e)) => Expr::Num(b.powf(e)),
+            (base, exponent) => Expr::Pow(Box::new(base), Box::new(exponent)),
+        }
+    }
+
+    fn is_num(&self, value: f64) -> bool {
+        matches!(self, Expr::Num(v) if *v == value)
+    }
+
+    fn count_symbols(&self, counts: &mut HashMap<String, usize>) {
+        match self {
+            Expr::Num(_) => {}
+            Expr::Sym(name) => *counts.entry(name.clone()).or_insert(0) += 1,
+            Expr::Neg(a) => a.count_symbols(counts),
+            Expr::Add(a, b) | Expr::Sub(a, b) | Expr::Mul(a, b) | Expr::Div(a, b) | Expr::Pow(a, b) => {
+                a.count_symbols(counts);
+                b.count_symbols(counts);
+            }
+            Expr::Call(_, args) => args.iter().for_each(|arg| arg.count_symbols(counts)),
+            Expr::Let(bindings, body) => {
+                bindings.iter().for_each(|(_, e)| e.count_symbols(counts));
+                body.count_symbols(counts);
+            }
+        }
+    }
+
+    // `self` with the symbol `name` replaced by `value`
+    fn substitute(&self, name: &str, value: &Expr) -> Expr {
+        let sub = |e: &Expr| Box::new(e.substitute(name, value));
+        match self {
+            Expr::Sym(symbol) if symbol == name => value.clone(),
+            Expr::Num(_) | Expr::Sym(_) => self.clone(),
+            Expr::Neg(a) => Expr::Neg(sub(a)),
+            Expr::Add(a, b) => Expr::Add(sub(a), sub(b)),
+            Expr::Sub(a, b) => Expr::Sub(sub(a), sub(b)),
+            Expr::Mul(a, b) => Expr::Mul(sub(a), sub(b)),
+            Expr::Div(a, b) => Expr::Div(sub(a), sub(b)),
+            Expr::Pow(a, b) => Expr::Pow(sub(a), sub(b)),
+            Expr::Call(function, args) => {
+                Expr::Call(function.clone(), args.iter().map(|arg| arg.substitute(name, value)).collect())
+            }
+            Expr::Let(bindings, body) => Expr::Let(
+                bindings.iter().map(|(n, e)| (n.clone(), e.substitute(name, value))).collect(),
+                sub(body),
+            ),
+        }
+    }
+
+    /// Binding strength in infix notation, atoms and calls bind the most.
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Let(..) => 0,
+            Expr::Add(..) | Expr::Sub(..) => 1,
+            Expr::Mul(..) | Expr::Div(..) => 2,
+            Expr::Neg(..) => 3,
+            Expr::Num(v) if *v < 0.0 => 3,
+            Expr::Pow(..) => 4,
+            _ => 5,
+        }
+    }
+
+    fn fmt_infix(&self, f: &mut fmt::Formatter<'_>, min: u8) -> fmt::Result {
+        if self.precedence() < min {
+            write!(f, "(")?;
+            self.fmt_infix(f, 0)?;
+            return write!(f, ")");
+        }
+        match self {
+            Expr::Num(v) => write!(f, "{}", v),
+            Expr::Sym(name) => write!(f, "{}", name),
+            Expr::Neg(a) => {
+                write!(f, "-")?;
+                a.fmt_infix(f, 3)
+            }
+            Expr::Add(a, b) => infix(f, a, " + ", b, 1, 1),
+            Expr::Sub(a, b) => infix(f, a, " - ", b, 1, 2),
+            Expr::Mul(a, b) => infix(f, a, " * ", b, 2, 2),
+            Expr::Div(a, b) => infix(f, a, " / ", b, 2, 3),
+            Expr::Pow(a, b) => infix(f, a, "^", b, 5, 4),
+            Expr::Call(name, args) => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    arg.fmt_infix(f, 0)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Let(bindings, body) => {
+                body.fmt_infix(f, 0)?;
+                write!(f, " where ")?;
+                for (i, (name, e)) in bindings.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{} = ", name)?;
+                    e.fmt_infix(f, 0)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// The expression in LaTeX math mode, without the surrounding `$`.
+    pub fn to_latex(&self) -> String {
+        let mut out = String::new();
+        self.write_latex(&mut out, 0);
+        out
+    }
+
+    // same as `precedence`, except that `\frac` needs no parentheses
+    fn latex_precedence(&self) -> u8 {
+        match self {
+            Expr::Div(..) => 5,
+            _ => self.precedence(),
+        }
+    }
+
+    fn write_latex(&self, out: &mut String, min: u8) {
+        if self.latex_precedence() < min {
+            out.push_str("\\left(");
+            self.write_latex(out, 0);
+            out.push_str("\\right)");
+            return;
+        }
+        match self {
+            Expr::Num(v) => out.push_str(&v.to_string()),
+            Expr::Sym(name) => out.push_str(name),
+            Expr::Neg(a) => {
+                out.push('-');
+                a.write_latex(out, 3);
+            }
+            Expr::Add(a, b) => {
+                a.write_latex(out, 1);
+                out.push_str(" + ");
+                b.write_latex(out, 1);
+            }
+            Expr::Sub(a, b) => {
+                a.write_latex(out, 1);
+                out.push_str(" - ");
+                b.write_latex(out, 2);
+            }
+            Expr::Mul(a, b) => {
+                a.write_latex(out, 2);
+                out.push_str(" \\cdot ");
+                b.write_latex(out, 3);
+            }
+            Expr::Div(a, b) => {
+                out.push_str("\\frac{");
+                a.write_latex(out, 0);
+                out.push_str("}{");
+                b.write_latex(out, 0);
+                out.push('}');
+            }
+            Expr::Pow(a, b) => {
+                a.write_latex(out, 5);
+                out.push_str("^{");
+                b.write_latex(out, 0);
+                out.push('}');
+            }
+            Expr::Call(name, args) if name == "sqrt" => {
+                out.push_str("\\sqrt{");
+                args[0].write_latex(out, 0);
+                out.push('}');
+            }
+            Expr::Call(name, args) if name == "ge" || name == "le" => {
+                // Iverson bracket
+                out.push_str("\\left[");
+                args[0].write_latex(out, 0);
+                out.push_str(if name == "ge" { " \\geq " } else { " \\leq " });
+                args[1].write_latex(out, 0);
+                out.push_str("\\right]");
+            }
+            Expr::Call(name, args) if name == "abs" => {
+                out.push_str("\\left|");
+                args[0].write_latex(out, 0);
+                out.push_str("\\right|");
+            }
+            Expr::Call(name, args) => {
+                // "name'" and "name'i" are derivatives of name
+                let (base, prime) = match name.find('\'') {
+                    Some(i) => name.split_at(i),
+                    None => (name.as_str(), ""),
+                };
+                match base {
+                    "tanh" | "exp" | "log" | "sin" | "cos" | "max" | "min" => out.push_str(&format!("\\{}", base)),
+                    _ => out.push_str(&format!("\\operatorname{{{}}}", base)),
+                }
+                if !prime.is_empty() {
+                    out.push('\'');
+                    if prime.len() > 1 {
+                        out.push_str(&format!("_{{{}}}", &prime[1..]));
+                    }
+                }
+                out.push_str("\\left(");
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    arg.write_latex(out, 0);
+                }
+                out.push_str("\\right)");
+            }
+            Expr::Let(bindings, body) => {
+                body.write_latex(out, 0);
+                out.push_str(" \\quad \\text{where } ");
+                for (i, (name, e)) in bindings.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    out.push_str(name);
+                    out.push_str(" = ");
+                    e.write_latex(out, 0);
+                }
+            }
+        }
+    }
+}
+
+fn infix(f: &mut fmt::Formatter<'_>, a: &Expr, op: &str, b: &Expr, min_a: u8, min_b: u8) -> fmt::Result {
+    a.fmt_infix(f, min_a)?;
+    write!(f, "{}", op)?;
+    b.fmt_infix(f, min_b)
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_infix(f, 0)
+    }
+}
+
+
+impl ops::Add<Expr> for Expr {
+    type Output = Expr;
+
+    fn add(self, rhs: Expr) -> Expr {
+        match (self, rhs) {
+            (Expr::Num(a), Expr::Num(b)) => Expr::Num(a + b),
+            (a, b) if b.is_num(0.0) => a,
+            (a, b) if a.is_num(0.0) => b,
+            (a, Expr::Neg(b)) => a - *b,
+            // x - 1 rather than x + -1
+            (a, Expr::Num(b)) if b < 0.0 => Expr::Sub(Box::new(a), Box::new(Expr::Num(-b))),
+            (a, b) => Expr::Add(Box::new(a), Box::new(b)),
+        }
+    }
+}
+
+impl ops::Sub<Expr> for Expr {
+    type Output = Expr;
+
+    fn sub(self, rhs: Expr) -> Expr {
+        match (self, rhs) {
+            (Expr::Num(a), Expr::Num(b)) => Expr::Num(a - b),
+            (a, b) if b.is_num(0.0) => a,
+            (a, b) if a.is_num(0.0) => -b,
+            (a, Expr::Neg(b)) => a + *b,
+            (a, Expr::Num(b)) if b < 0.0 => Expr::Add(Box::new(a), Box::new(Expr::Num(-b))),
+            (a, b) => Expr::Sub(Box::new(a), Box::new(b)),
+        }
+    }
+}
+
+impl ops::Mul<Expr> for Expr {
+    type Output = Expr;
+
+    fn mul(self, rhs: Expr) -> Expr {
+        match (self, rhs) {
+            (Expr::Num(a), Expr::Num(b)) => Expr::Num(a * b),
+            (a, b) if a.is_num(0.0) || b.is_num(0.0) => Expr::Num(0.0),
+            (a, b) if b.is_num(1.0) => a,
+            (a, b) if a.is_num(1.0) => b,
+            (a, b) if a.is_num(-1.0) => -b,
+            (a, b) if b.is_num(-1.0) => -a,
+            (Expr::Neg(a), Expr::Neg(b)) => *a * *b,
+            // numbers go first: 2 * x
+            (a, Expr::Num(b)) => Expr::Mul(Box::new(Expr::Num(b)), Box::new(a)),
+            (a, b) => Expr::Mul(Box::new(a), Box::new(b)),
+        }
+    }
+}
+
+impl ops::Div<Expr> for Expr {
+    type Output = Expr;
+
+    fn div(self, rhs: Expr) -> Expr {
+        match (self, rhs) {
+            (Expr::Num(a), Expr::Num(b)) if b != 0.0 => Expr::Num(a / b),
+            (a, b) if b.is_num(1.0) => a,
+            (a, _) if a.is_num(0.0) => Expr::Num(0.0),
+            (a, b) => Expr::Div(Box::new(a), Box::new(b)),
+        }
+    }
+}
+
+impl ops::Neg for Expr {
+    type Output = Expr;
+
+    fn neg(self) -> Expr {
+        match self {
+            Expr::Num(v) => Expr::Num(-v),
+            Expr::Neg(a) => *a,
+            a => Expr::Neg(Box::new(a)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::leaf;
+
+    #[test]
+    fn neuron_derivative() {
+        let x = leaf(0.5, "x");
+        let w = leaf(-1.0, "w");
+        let b = leaf(2.0, "b");
+        let out = (x.clone() * w.clone() + b.clone()).tanh();
+
+        assert_eq!(expression(&out).to_string(), "tanh(x * w + b)");
+        let dx = derivative(&out, &x);
+        assert_eq!(dx.to_string(), "(1 - tanh(x * w + b)^2) * w");
+        assert_eq!(dx.to_latex(), "\\left(1 - \\tanh\\left(x \\cdot w + b\\right)^{2}\\right) \\cdot w");
+        assert_eq!(derivative(&out, &b).to_string(), "1 - tanh(x * w + b)^2");
+    }
+
+    #[test]
+    fn simplification() {
+        let x = leaf(2.0, "x");
+        let y = leaf(3.0, "y");
+        assert_eq!(derivative(&(x.clone() * y.clone() + 1.0), &x).to_string(), "y");
+        assert_eq!(derivative(&x.clone().pow(3.0), &x).to_string(), "3 * x^2");
+        assert_eq!(derivative(&(x.clone() * 4.0 - y.clone()), &y).to_string(), "-1");
+
+        let quotient = x.clone() / y.clone();
+        assert_eq!(derivative(&quotient, &x).to_string(), "1 / y");
+        assert_eq!(derivative(&quotient, &y).to_string(), "-(x / y^2)");
+        assert_eq!(derivative(&quotient, &y).to_latex(), "-\\frac{x}{y^{2}}");
+
+        // not in the graph
+        assert_eq!(derivative(&quotient, &leaf(1.0, "z")).to_string(), "0");
+    }
+
+    #[test]
+    fn function_names() {
+        let x = leaf(2.0, "x");
+        let v = Value::new(1.0);
+        let out = x.clone().sqrt() + x.clone().abs().log() * v.clone();
+
+        let dx = derivative(&out, &x);
+        assert_eq!(dx.to_string(), format!("1 / (2 * sqrt(x)) + sign(x) / abs(x) * v{}", v.id()));
+        assert_eq!(
+            dx.to_latex(),
+            format!(
+                "\\frac{{1}}{{2 \\cdot \\sqrt{{x}}}} + \\frac{{\\operatorname{{sign}}\\left(x\\right)}}{{\\left|x\\right|}} \\cdot v{}",
+                v.id()
+            )
+        );
+        assert_eq!(derivative(&x.clone().gelu(), &x).to_latex(), "\\operatorname{gelu}'\\left(x\\right)");
+    }
+
+    #[test]
+    fn binary_ops() {
+        let a = leaf(2.0, "a");
+        let b = leaf(3.0, "b");
+        let out = a.clone().pow_value(b.clone());
+        assert_eq!(derivative(&out, &a).to_string(), "b * a^(b - 1)");
+        assert_eq!(derivative(&out, &b).to_string(), "a^b * log(a)");
+        let max = a.clone().max(b.clone());
+        assert_eq!(derivative(&max, &a).to_string(), "ge(a, b)");
+        assert_eq!(derivative(&max, &b).to_string(), "1 - ge(a, b)");
+        assert_eq!(derivative(&a.clone().min(b.clone()), &a).to_latex(), "\\left[a \\leq b\\right]");
+    }
+
+    #[test]
+    fn ties_follow_backward() {
+        let a = leaf(1.0, "a");
+        let b = leaf(1.0, "b");
+        let out = a.clone().max(b.clone()) + a.clone().min(b.clone()) * 2.0;
+        out.backward();
+        // ge(a, b) = le(a, b) = 1 at a tie
+        assert_eq!(derivative(&out, &a).to_string(), "ge(a, b) + 2 * le(a, b)");
+        assert_eq!(a.gradient(), 3.0);
+        assert_eq!(derivative(&out, &b).to_string(), "1 - ge(a, b) + 2 * (1 - le(a, b))");
+        assert_eq!(b.gradient(), 0.0);
+    }
+
+    #[test]
+    fn shared_nodes_are_named() {
+        let a = leaf(2.0, "a");
+        let b = leaf(3.0, "b");
+        let s = a.clone() + b.clone();
+        s.set_label("s");
+        let out = (s.clone() * s.clone()).tanh();
+        assert_eq!(expression(&out).to_string(), "tanh(s * s) where s = a + b");
+        assert_eq!(derivative(&out, &a).to_string(), "(1 - tanh(s * s)^2) * (s + s) where s = a + b");
+        assert_eq!(
+            expression(&out).to_latex(),
+            "\\tanh\\left(s \\cdot s\\right) \\quad \\text{where } s = a + b"
+        );
+
+        // used once in the result: inlined
+        let used_once = s.clone() * s.clone() * 0.0 + s.clone().exp();
+        assert_eq!(derivative(&used_once, &b).to_string(), "exp(a + b)");
+
+        // 2^40 leaves without the names
+        let x = leaf(0.5, "x");
+        let mut y = x.clone();
+        for _ in 0..40 {
+            y = (y.clone() * y).tanh();
+        }
+        let dy = derivative(&y, &x).to_string();
+        assert!(dy.len() < 10_000, "{}", dy.len());
+        assert!(dy.contains(" where "));
+    }
+
+    #[test]
+    fn clashing_names_are_renamed() {
+        let a = leaf(2.0, "a");
+        let b = leaf(3.0, "b");
+        let s = a.clone() + b.clone();
+        s.set_label("s");
+        let t = a.clone() * b.clone();
+        t.set_label("s");
+        let out = s.clone() * s.clone() + t.clone() * t.clone();
+        assert_eq!(expression(&out).to_string(), "t4 * t4 + s * s where s = a * b, t4 = a + b");
+        assert_eq!(
+            derivative(&out, &a).to_string(),
+            "t4 + t4 + b * s + s * b where s = a * b, t4 = a + b"
+        );
+
+        // `ds` would name the derivative of `s`
+        let ds = leaf(3.0, "ds");
+        let s = a.clone() + ds.clone();
+        s.set_label("s");
+        let out = (s.clone() * s).tanh();
+        assert_eq!(expression(&out).to_string(), "tanh(t2 * t2) where t2 = a + ds");
+    }
+
+    #[test]
+    fn negative_constants() {
+        let x = leaf(2.0, "x");
+        assert_eq!(expression(&(x.clone() + -1.0)).to_string(), "x - 1");
+        assert_eq!(expression(&(x.clone() - -1.5)).to_string(), "x + 1.5");
+    }
+}
@@ -0,0 +1,510 @@
+use std::ops;
+use std::{cell::RefCell, collections::HashMap, sync::Arc};
+use std::fmt;
+
+use crate::{Function, Op, Value};
+
+/// A node of the tape: the op and the range of its parents in `TapeData::parents`.
+struct Node {
+    op: Op,
+    value: f64,
+    parents_start: usize,
+    parents_len: usize,
+}
+
+struct TapeData {
+    nodes: Vec<Node>,
+    parents: Vec<usize>,
+    // tape index of every `Value` loaded with `Tape::param`, keyed by node
+    // identity (`param_values` keeps the nodes alive)
+    params: HashMap<usize, usize>,
+    param_values: Vec<(usize, Value)>,
+}
+
+/// An arena based alternative to the `Value` graph.
+///
+/// Nodes are appended to one contiguous `Vec` in creation order, which is a
+/// topological order, so `backward` is a single reverse sweep over the tape
+/// without sorting, hashing or per-node allocations.
+pub struct Tape {
+    data: RefCell<TapeData>,
+}
+
+/// A handle to a node on a `Tape`, cheap to copy.
+#[derive(Clone, Copy)]
+pub struct Var<'t> {
+    tape: &'t Tape,
+    index: usize,
+}
+
+/// The gradients of a `Tape::backward` pass, indexed by `Var`.
+#[derive(Clone, Debug)]
+pub struct Gradients(Vec<f64>);
+
+impl Default for Tape {
+    fn default() -> Self {
+        Tape::new()
+    }
+}
+
+impl Tape {
+    pub fn new() -> Tape {
+        Tape {
+            data: RefCell::new(TapeData {
+                nodes: Vec::new(),
+                parents: Vec::new(),
+                params: HashMap::new(),
+                param_values: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.borrow().nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops all the nodes but keeps the allocated memory, so the tape can be
+    /// reused for the next training step.
+    pub fn clear(&mut self) {
+        let data = self.data.get_mut();
+        data.nodes.clear();
+        data.parents.clear();
+        data.params.clear();
+        data.param_values.clear();
+    }
+
+    fn push(&self, value: f64, op: Op, parents: &[usize]) -> Var<'_> {
+        let mut data = self.data.borrow_mut();
+        let index = data.nodes.len();
+        let parents_start = data.parents.len();
+        data.parents.extend_from_slice(parents);
+        data.nodes.push(Node {
+            op,
+            value,
+            parents_start,
+            parents_len: parents.len(),
+        });
+        Var { tape: self, index }
+    }
+
+    fn push_op(&self, op: Op, parents: &[usize]) -> Var<'_> {
+        let value = {
+            let data = self.data.borrow();
+            match parents {
+                [a] => op.forward(&[data.nodes[*a].value]),
+                [a, b] => op.forward(&[data.nodes[*a].value, data.nodes[*b].value]),
+                _ => {
+                    let inputs: Vec<f64> = parents.iter().map(|p| data.nodes[*p].value).collect();
+                    op.forward(&inputs)
+                }
+            }
+        };
+        self.push(value, op, parents)
+    }
+
+    /// A leaf we want the gradient of.
+    pub fn var(&self, value: f64) -> Var<'_> {
+        self.push(value, Op::None, &[])
+    }
+
+    pub fn vars(&self, values: &[f64]) -> Vec<Var<'_>> {
+        values.iter().map(|value| self.var(*value)).collect()
+    }
+
+    pub fn constant(&self, value: f64) -> Var<'_> {
+        self.push(value, Op::Const, &[])
+    }
+
+    /// Loads a parameter of the `Value` engine (e.g. a `Neuron` weight) on the
+    /// tape. Loading the same `Value` twice returns the same `Var`, and
+    /// `backward` writes the gradient back into the `Value`.
+    pub fn param(&self, value: &Value) -> Var<'_> {
+        if let Some(index) = self.data.borrow().params.get(&value.key()) {
+            return Var { tape: self, index: *index };
+        }
+        let var = self.var(value.value());
+        let mut data = self.data.borrow_mut();
+        data.params.insert(value.key(), var.index);
+        data.param_values.push((var.index, value.clone()));
+        var
+    }
+
+    /// Applies a user-defined `Function` to `inputs`.
+    pub fn apply<'t>(&'t self, function: Arc<dyn Function>, inputs: &[Var<'t>]) -> Var<'t> {
+        let parents: Vec<usize> = inputs.iter().map(|input| input.index).collect();
+        self.push_op(Op::Custom(function), &parents)
+    }
+
+    /// Backpropagates from `root` with a reverse sweep over the tape. The
+    /// gradients of the `Value`s loaded with `param` are set as well.
+    pub fn backward(&self, root: Var<'_>) -> Gradients {
+        self.sweep(root, false)
+    }
+
+    /// Like `backward`, but adds to the gradients of the `param` values
+    /// instead of overwriting them. Threads running their own tape over a
+    /// shared model reduce their gradients into the same parameters this way.
+    pub fn backward_accumulate(&self, root: Var<'_>) -> Gradients {
+        self.sweep(root, true)
+    }
+
+    fn sweep(&self, root: Var<'_>, accumulate: bool) -> Gradients {
+        let data = self.data.borrow();
+        let mut gradients = vec![0.0; data.nodes.len()];
+        gradients[root.index] = 1.0;
+
+        let mut inputs: Vec<f64> = Vec::new();
+        let mut locals: Vec<f64> = Vec::new();
+        for index in (0..=root.index).rev() {
+            let node = &data.nodes[index];
+            if node.parents_len == 0 {
+                continue;
+            }
+            let parents = &data.parents[node.parents_start..node.parents_start + node.parents_len];
+            inputs.clear();
+            inputs.extend(parents.iter().map(|p| data.nodes[*p].value));
+            locals.clear();
+            locals.resize(parents.len(), 0.0);
+            node.op.local_gradients(&inputs, node.value, &mut locals);
+            let out_gradient = gradients[index];
+            for (parent, local) in parents.iter().zip(locals.iter()) {
+                gradients[*parent] += local * out_gradient;
+            }
+        }
+
+        for (index, value) in data.param_values.iter() {
+            if accumulate {
+                value.inc_gradient(gradients[*index], "accumulate");
+            } else {
+                value.set_gradient(gradients[*index]);
+            }
+        }
+        Gradients(gradients)
+    }
+}
+
+impl Gradients {
+    pub fn wrt(&self, var: Var<'_>) -> f64 {
+        self.0[var.index]
+    }
+}
+
+impl<'t> Var<'t> {
+    pub fn value(&self) -> f64 {
+        self.tape.data.borrow().nodes[self.index].value
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn op(&self) -> Op {
+        self.tape.data.borrow().nodes[self.index].op.clone()
+    }
+
+    fn unary_op(self, op: Op) -> Var<'t> {
+        self.tape.push_op(op, &[self.index])
+    }
+
+    fn binary_op(self, op: Op, rhs: Var<'t>) -> Var<'t> {
+        assert!(std::ptr::eq(self.tape, rhs.tape), "vars belong to different tapes");
+        self.tape.push_op(op, &[self.index, rhs.index])
+    }
+
+    pub fn tanh(self) -> Var<'t> {
+        self.unary_op(Op::Tanh)
+    }
+
+    pub fn exp(self) -> Var<'t> {
+        self.unary_op(Op::Exp)
+    }
+
+    pub fn pow(self, value: f64) -> Var<'t> {
+        self.unary_op(Op::Pow(value))
+    }
+
+    pub fn relu(self) -> Var<'t> {
+        self.unary_op(Op::Relu)
+    }
+
+    pub fn log(self) -> Var<'t> {
+        self.unary_op(Op::Log)
+    }
+
+    pub fn sqrt(self) -> Var<'t> {
+        self.unary_op(Op::Sqrt)
+    }
+
+    pub fn sin(self) -> Var<'t> {
+        self.unary_op(Op::Sin)
+    }
+
+    pub fn cos(self) -> Var<'t> {
+        self.unary_op(Op::Cos)
+    }
+
+    pub fn sigmoid(self) -> Var<'t> {
+        self.unary_op(Op::Sigmoid)
+    }
+
+    pub fn softplus(self) -> Var<'t> {
+        self.unary_op(Op::Softplus)
+    }
+
+    pub fn abs(self) -> Var<'t> {
+        self.unary_op(Op::Abs)
+    }
+
+    pub fn leaky_relu(self, alpha: f64) -> Var<'t> {
+        self.unary_op(Op::LeakyRelu(alpha))
+    }
+
+    pub fn gelu(self) -> Var<'t> {
+        self.unary_op(Op::Gelu)
+    }
+
+    pub fn pow_value(self, exponent: Var<'t>) -> Var<'t> {
+        self.binary_op(Op::PowValue, exponent)
+    }
+
+    pub fn max(self, rhs: Var<'t>) -> Var<'t> {
+        self.binary_op(Op::Max, rhs)
+    }
+
+    pub fn min(self, rhs: Var<'t>) -> Var<'t> {
+        self.binary_op(Op::Min, rhs)
+    }
+
+    pub fn atan2(self, x: Var<'t>) -> Var<'t> {
+        self.binary_op(Op::Atan2, x)
+    }
+}
+
+impl fmt::Debug for Var<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Var[{}, value={}, op={:?}]", self.index, self.value(), self.op())
+    }
+}
+
+impl fmt::Display for Var<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Var[{}, op={:?}]", self.value(), self.op())
+    }
+}
+
+
+impl<'t> ops::Add<Var<'t>> for Var<'t> {
+    type Output = Var<'t>;
+
+    fn add(self, rhs: Var<'t>) -> Var<'t> {
+        self.binary_op(Op::Add, rhs)
+    }
+}
+
+impl<'t> ops::Sub<Var<'t>> for Var<'t> {
+    type Output = Var<'t>;
+
+    fn sub(self, rhs: Var<'t>) -> Var<'t> {
+        self.binary_op(Op::Sub, rhs)
+    }
+}
+
+impl<'t> ops::Mul<Var<'t>> for Var<'t> {
+    type Output = Var<'t>;
+
+    fn mul(self, rhs: Var<'t>) -> Var<'t> {
+        self.binary_op(Op::Mul, rhs)
+    }
+}
+
+impl<'t> ops::Div<Var<'t>> for Var<'t> {
+    type Output = Var<'t>;
+
+    fn div(self, rhs: Var<'t>) -> Var<'t> {
+        self.binary_op(Op::Div, rhs)
+    }
+}
+
+impl<'t> ops::Neg for Var<'t> {
+    type Output = Var<'t>;
+
+    fn neg(self) -> Var<'t> {
+        self.unary_op(Op::Neg)
+    }
+}
+
+impl<'t> ops::Add<f64> for Var<'t> {
+    type Output = Var<'t>;
+
+    fn add(self, rhs: f64) -> Var<'t> {
+        self + self.tape.constant(rhs)
+    }
+}
+
+impl<'t> ops::Sub<f64> for Var<'t> {
+    type Output = Var<'t>;
+
+    fn sub(self, rhs: f64) -> Var<'t> {
+        self - self.tape.constant(rhs)
+    }
+}
+
+impl<'t> ops::Mul<f64> for Var<'t> {
+    type Output = Var<'t>;
+
+    fn mul(self, rhs: f64) -> Var<'t> {
+        self * self.tape.constant(rhs)
+    }
+}
+
+impl<'t> ops::Div<f64> for Var<'t> {
+    type Output = Var<'t>;
+
+    fn div(self, rhs: f64) -> Var<'t> {
+        self / self.tape.constant(rhs)
+    }
+}
+
+impl<'t> ops::Add<Var<'t>> for f64 {
+    type Output = Var<'t>;
+
+    fn add(self, rhs: Var<'t>) -> Var<'t> {
+        rhs.tape.constant(self) + rhs
+    }
+}
+
+impl<'t> ops::Sub<Var<'t>> for f64 {
+    type Output = Var<'t>;
+
+    fn sub(self, rhs: Var<'t>) -> Var<'t> {
+        rhs.tape.constant(self) - rhs
+    }
+}
+
+impl<'t> ops::Mul<Var<'t>> for f64 {
+    type Output = Var<'t>;
+
+    fn mul(self, rhs: Var<'t>) -> Var<'t> {
+        rhs.tape.constant(self) * rhs
+    }
+}
+
+impl<'t> ops::Div<Var<'t>> for f64 {
+    type Output = Var<'t>;
+
+    fn div(self, rhs: Var<'t>) -> Var<'t> {
+        rhs.tape.constant(self) / rhs
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abc_backpropagation() {
+        let tape = Tape::new();
+        let a = tape.var(2.0);
+        let b = tape.var(-3.0);
+        let c = tape.var(10.0);
+        let d = a * b + c;
+        let e = (d / 2.0).tanh() + (1.0 - a).pow(2.0) - 3.0 * (-c).exp() + b.relu();
+        assert_eq!(tape.len(), 19);
+
+        let grads = tape.backward(e);
+        let t = 2.0_f64.tanh();
+        assert_approx!(d.value(), 4.0);
+        assert_approx!(grads.wrt(a), (1.0 - t * t) / 2.0 * -3.0 + 2.0);
+        assert_approx!(grads.wrt(b), (1.0 - t * t) / 2.0 * 2.0);
+        assert_approx!(grads.wrt(c), (1.0 - t * t) / 2.0 + 3.0 * (-10.0_f64).exp());
+    }
+
+    #[test]
+    fn matches_value_engine() {
+        let x = Value::vec(&[0.3, -1.2, 2.5]);
+        let out = (x[0].clone() * x[1].clone() + x[2].clone()).tanh() / (x[1].clone().pow(2.0) + 1.0);
+        out.backward();
+
+        let tape = Tape::new();
+        let v: Vec<Var> = x.iter().map(|x| tape.param(x)).collect();
+        let tape_out = (v[0] * v[1] + v[2]).tanh() / (v[1].pow(2.0) + 1.0);
+        let grads = tape.backward(tape_out);
+
+        assert_approx!(tape_out.value(), out.value());
+        for (value, var) in x.iter().zip(v.iter()) {
+            assert_approx!(grads.wrt(*var), value.gradient());
+        }
+    }
+
+    #[test]
+    fn unary_functions_match_value_engine() {
+        let x = Value::new(0.7);
+        let out = x.clone().log() + x.clone().sqrt() * x.clone().sin() - x.clone().cos().sigmoid()
+            + x.clone().softplus() / (-x.clone()).abs() + (-x.clone()).leaky_relu(0.2) * x.clone().gelu();
+        out.backward();
+
+        let tape = Tape::new();
+        let v = tape.var(0.7);
+        let tape_out = v.log() + v.sqrt() * v.sin() - v.cos().sigmoid()
+            + v.softplus() / (-v).abs() + (-v).leaky_relu(0.2) * v.gelu();
+        let grads = tape.backward(tape_out);
+        assert_approx!(tape_out.value(), out.value());
+        assert_approx!(grads.wrt(v), x.gradient());
+    }
+
+    #[test]
+    fn binary_functions_match_value_engine() {
+        let x = Value::new(1.3);
+        let y = Value::new(-0.6);
+        let out = x.clone().pow_value(y.clone()) + x.clone().max(y.clone()) * y.clone().min(x.clone())
+            - y.clone().atan2(x.clone());
+        out.backward();
+
+        let tape = Tape::new();
+        let (a, b) = (tape.param(&x), tape.param(&y));
+        let tape_out = a.pow_value(b) + a.max(b) * b.min(a) - b.atan2(a);
+        let grads = tape.backward(tape_out);
+        assert_approx!(tape_out.value(), out.value());
+        assert_approx!(grads.wrt(a), x.gradient());
+        assert_approx!(grads.wrt(b), y.gradient());
+    }
+
+    #[test]
+    fn params_are_loaded_once() {
+        let w = Value::new(3.0);
+        let mut tape = Tape::new();
+        let a = tape.param(&w);
+        let b = tape.param(&w);
+        assert_eq!(a.index(), b.index());
+
+        let grads = tape.backward(a * b);
+        assert_approx!(grads.wrt(a), 6.0);
+        assert_approx!(w.gradient(), 6.0);
+
+        tape.clear();
+        assert!(tape.is_empty());
+    }
+
+    #[test]
+    fn anomaly_in_accumulation() {
+        let w = Value::new(1.0);
+        w.set_label("w");
+        let tape = Tape::new();
+        let a = tape.param(&w);
+        tape.backward_accumulate(a * 1e308);
+
+        let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            crate::detect_anomaly(|| tape.backward_accumulate(a * 1e308));
+        }))
+        .unwrap_err();
+        let message = payload.downcast_ref::<String>().unwrap();
+        assert!(message.starts_with("anomaly: inf gradient of accumulate node w"), "{}", message);
+        assert!(message.ends_with("path w"), "{}", message);
+        assert!(w.gradient().is_infinite());
+    }
+}
@@ -0,0 +1,512 @@
+use std::fmt;
+use std::ops;
+
+use rand::distributions::{Distribution, Uniform};
+
+use crate::{BabygradError, Value};
+
+/// An n-dimensional array of `Value`s, stored row-major. Every element is a
+/// node of the scalar engine, so the ops build an ordinary `Value` graph and
+/// `backward` on a scalar result fills in the gradients of all elements.
+///
+/// Elementwise ops broadcast like NumPy: the shapes are aligned on their
+/// last dimension, and a missing dimension or a dimension of 1 is repeated
+/// to match the other side.
+#[derive(Clone, Debug)]
+pub struct Tensor {
+    data: Vec<Value>,
+    shape: Vec<usize>,
+    strides: Vec<usize>,
+}
+
+impl Tensor {
+    /// Panics when `values` does not fill `shape`, see `from_values`.
+    pub fn new(values: &[f64], shape: &[usize]) -> Tensor {
+        Tensor::from_values(Value::vec(values), shape).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// A tensor over existing nodes, e.g. the weights of a model, so their
+    /// gradients are filled in by `backward`.
+    pub fn from_values(data: Vec<Value>, shape: &[usize]) -> Result<Tensor, BabygradError> {
+        let expected: usize = shape.iter().product();
+        if data.len() != expected {
+            return Err(BabygradError::Elements {
+                expected,
+                found: data.len(),
+            });
+        }
+        Ok(Tensor {
+            data,
+            shape: shape.to_vec(),
+            strides: strides(shape),
+        })
+    }
+
+    /// A tensor of shape `[]` holding one number.
+    pub fn scalar(value: f64) -> Tensor {
+        Tensor::new(&[value], &[])
+    }
+
+    pub fn zeros(shape: &[usize]) -> Tensor {
+        Tensor::new(&vec![0.0; shape.iter().product()], shape)
+    }
+
+    /// Uniformly distributed in [-1, 1], like the weights of a `Neuron`.
+    pub fn rand(shape: &[usize]) -> Tensor {
+        let mut rng = rand::thread_rng();
+        let uniform = Uniform::new_inclusive(-1.0, 1.0);
+        let values: Vec<f64> = (0..shape.iter().product()).map(|_| uniform.sample(&mut rng)).collect();
+        Tensor::new(&values, shape)
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    /// Elements to skip to move by one along each dimension.
+    pub fn strides(&self) -> &[usize] {
+        &self.strides
+    }
+
+    pub fn ndim(&self) -> usize {
+        self.shape.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// The elements in row-major order.
+    pub fn values(&self) -> &[Value] {
+        &self.data
+    }
+
+    pub fn get(&self, index: &[usize]) -> Value {
+        assert_eq!(index.len(), self.ndim(), "index {:?} for shape {:?}", index, self.shape);
+        let offset: usize = index
+            .iter()
+            .zip(self.shape.iter().zip(self.strides.iter()))
+            .map(|(&i, (&size, &stride))| {
+                assert!(i < size, "index {:?} out of bounds for shape {:?}", index, self.shape);
+                i * stride
+            })
+            .sum();
+        self.data[offset].clone()
+    }
+
+    /// The numbers held by the elements, row-major.
+    pub fn to_vec(&self) -> Vec<f64> {
+        self.data.iter().map(Value::value).collect()
+    }
+
+    pub fn gradients(&self) -> Vec<f64> {
+        self.data.iter().map(Value::gradient).collect()
+    }
+
+    /// The same elements under another shape with as many elements.
+    pub fn reshape(&self, shape: &[usize]) -> Result<Tensor, BabygradError> {
+        Tensor::from_values(self.data.clone(), shape)
+    }
+
+    /// Swaps the last two dimensions, a matrix `[n, m]` becomes `[m, n]`.
+    pub fn transpose(&self) -> Tensor {
+        let n = self.ndim();
+        assert!(n >= 2, "transpose of a tensor of shape {:?}", self.shape);
+        let mut shape = self.shape.clone();
+        shape.swap(n - 2, n - 1);
+        let mut source_strides = self.strides.clone();
+        source_strides.swap(n - 2, n - 1);
+        let data = (0..self.len())
+            .map(|offset| self.data[source_offset(offset, &shape, &source_strides)].clone())
+            .collect();
+        Tensor::from_values(data, &shape).unwrap()
+    }
+
+    /// Applies `f` to every element.
+    pub fn map(&self, f: impl Fn(Value) -> Value) -> Tensor {
+        Tensor {
+            data: self.data.iter().cloned().map(f).collect(),
+            shape: self.shape.clone(),
+            strides: self.strides.clone(),
+        }
+    }
+
+    /// Combines the elements of `self` and `other` pairwise after
+    /// broadcasting them to a common shape, panics when there is none.
+    pub fn zip_with(&self, other: &Tensor, f: impl Fn(Value, Value) -> Value) -> Tensor {
+        self.try_zip_with(other, f).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    pub fn try_zip_with(&self, other: &Tensor, f: impl Fn(Value, Value) -> Value) -> Result<Tensor, BabygradError> {
+        let shape = broadcast_shape(&self.shape, &other.shape)?;
+        let lhs = broadcast_strides(&self.shape, &self.strides, shape.len());
+        let rhs = broadcast_strides(&other.shape, &other.strides, shape.len());
+        let data = (0..shape.iter().product())
+            .map(|offset| {
+                f(
+                    self.data[source_offset(offset, &shape, &lhs)].clone(),
+                    other.data[source_offset(offset, &shape, &rhs)].clone(),
+                )
+            })
+            .collect();
+        Tensor::from_values(data, &shape)
+    }
+
+    /// Matrix product. Vectors are treated as a row (on the left) or a
+    /// column (on the right) and the dimension is removed from the result:
+    /// `[n, k] x [k, m] = [n, m]`, `[k] x [k, m] = [m]`, `[n, k] x [k] = [n]`
+    /// and `[k] x [k] = []`.
+    ///
+    /// There is no fused op: every element of the result is a chain of `k`
+    /// scalar products and `k - 1` sums, so the product adds `n * m * (2k - 1)`
+    /// nodes to the graph. Fine for the small layers of this crate, slow and
+    /// memory hungry for large matrices.
+    pub fn matmul(&self, other: &Tensor) -> Tensor {
+        self.try_matmul(other).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    pub fn try_matmul(&self, other: &Tensor) -> Result<Tensor, BabygradError> {
+        let mismatch = || BabygradError::Broadcast {
+            lhs: self.shape.clone(),
+            rhs: other.shape.clone(),
+        };
+        let (n, k) = match self.shape[..] {
+            [k] if k > 0 => (1, k),
+            [n, k] if k > 0 => (n, k),
+            _ => return Err(mismatch()),
+        };
+        let m = match other.shape[..] {
+            [rows] if rows == k => 1,
+            [rows, m] if rows == k => m,
+            _ => return Err(mismatch()),
+        };
+        let mut data = Vec::with_capacity(n * m);
+        for i in 0..n {
+            for j in 0..m {
+                let mut sum = self.data[i * k].clone() * other.data[j].clone();
+                for l in 1..k {
+                    sum = sum + self.data[i * k + l].clone() * other.data[l * m + j].clone();
+                }
+                data.push(sum);
+            }
+        }
+        let mut shape = Vec::new();
+        if self.ndim() == 2 {
+            shape.push(n);
+        }
+        if other.ndim() == 2 {
+            shape.push(m);
+        }
+        Tensor::from_values(data, &shape)
+    }
+
+    /// Sum of all the elements, of shape `[]`.
+    pub fn sum(&self) -> Tensor {
+        let sum = self.data.iter().cloned().reduce(|sum, x| sum + x).unwrap_or_else(|| Value::constant(0.0));
+        Tensor::from_values(vec![sum], &[]).unwrap()
+    }
+
+    /// Mean of all the elements, of shape `[]`, 0 for an empty tensor.
+    pub fn mean(&self) -> Tensor {
+        if self.is_empty() {
+            return self.sum();
+        }
+        let count = self.len() as f64;
+        self.sum().map(|sum| sum / count)
+    }
+
+    /// Sums along `axis`, which is removed from the shape. Panics when the
+    /// tensor has no such axis, see `try_sum_axis`.
+    pub fn sum_axis(&self, axis: usize) -> Tensor {
+        self.try_sum_axis(axis).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    pub fn try_sum_axis(&self, axis: usize) -> Result<Tensor, BabygradError> {
+        self.check_axis(axis)?;
+        let mut shape = self.shape.clone();
+        let size = shape.remove(axis);
+        let mut source_strides = self.strides.clone();
+        let stride = source_strides.remove(axis);
+        let data = (0..shape.iter().product())
+            .map(|offset| {
+                let start = source_offset(offset, &shape, &source_strides);
+                (0..size)
+                    .map(|i| self.data[start + i * stride].clone())
+                    .reduce(|sum, x| sum + x)
+                    .unwrap_or_else(|| Value::constant(0.0))
+            })
+            .collect();
+        Tensor::from_values(data, &shape)
+    }
+
+    /// Means along `axis`, 0 along an axis of size 0.
+    pub fn mean_axis(&self, axis: usize) -> Tensor {
+        self.try_mean_axis(axis).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    pub fn try_mean_axis(&self, axis: usize) -> Result<Tensor, BabygradError> {
+        let sum = self.try_sum_axis(axis)?;
+        let count = self.shape[axis] as f64;
+        if count == 0.0 {
+            return Ok(sum);
+        }
+        Ok(sum.map(|sum| sum / count))
+    }
+
+    fn check_axis(&self, axis: usize) -> Result<(), BabygradError> {
+        if axis >= self.ndim() {
+            return Err(BabygradError::Axis {
+                axis,
+                ndim: self.ndim(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn tanh(&self) -> Tensor {
+        self.map(Value::tanh)
+    }
+
+    pub fn relu(&self) -> Tensor {
+        self.map(Value::relu)
+    }
+
+    pub fn sigmoid(&self) -> Tensor {
+        self.map(Value::sigmoid)
+    }
+
+    pub fn gelu(&self) -> Tensor {
+        self.map(Value::gelu)
+    }
+
+    pub fn exp(&self) -> Tensor {
+        self.map(Value::exp)
+    }
+
+    pub fn log(&self) -> Tensor {
+        self.map(Value::log)
+    }
+
+    pub fn pow(&self, exponent: f64) -> Tensor {
+        self.map(|x| x.pow(exponent))
+    }
+
+    /// Backpropagates from a tensor holding a single element, e.g. the
+    /// result of `sum` or `mean`.
+    pub fn backward(&self) {
+        assert_eq!(self.len(), 1, "backward from a tensor of shape {:?}", self.shape);
+        self.data[0].backward();
+    }
+
+    pub fn try_backward(&self) -> Result<(), BabygradError> {
+        match &self.data[..] {
+            [root] => root.try_backward(),
+            _ => Err(BabygradError::Elements {
+                expected: 1,
+                found: self.len(),
+            }),
+        }
+    }
+}
+
+fn strides(shape: &[usize]) -> Vec<usize> {
+    let mut strides = vec![1; shape.len()];
+    for i in (0..shape.len().saturating_sub(1)).rev() {
+        strides[i] = strides[i + 1] * shape[i + 1];
+    }
+    strides
+}
+
+// the offset in a tensor with `strides` of the element at row-major position
+// `offset` of a tensor of shape `shape`
+fn source_offset(mut offset: usize, shape: &[usize], strides: &[usize]) -> usize {
+    let mut source = 0;
+    for (&size, &stride) in shape.iter().zip(strides.iter()).rev() {
+        source += (offset % size) * stride;
+        offset /= size;
+    }
+    source
+}
+
+fn broadcast_shape(lhs: &[usize], rhs: &[usize]) -> Result<Vec<usize>, BabygradError> {
+    let ndim = lhs.len().max(rhs.len());
+    let dim = |shape: &[usize], i: usize| if i + shape.len() < ndim { 1 } else { shape[i + shape.len() - ndim] };
+    (0..ndim)
+        .map(|i| match (dim(lhs, i), dim(rhs, i)) {
+            (a, b) if a == b || b == 1 => Ok(a),
+            (1, b) => Ok(b),
+            _ => Err(BabygradError::Broadcast {
+                lhs: lhs.to_vec(),
+                rhs: rhs.to_vec(),
+            }),
+        })
+        .collect()
+}
+
+// the strides of `shape` seen as a `ndim` dimensional shape, 0 along the
+// dimensions repeated by broadcasting
+fn broadcast_strides(shape: &[usize], strides: &[usize], ndim: usize) -> Vec<usize> {
+    let mut result = vec![0; ndim - shape.len()];
+    result.extend(shape.iter().zip(strides.iter()).map(|(&size, &stride)| if size == 1 { 0 } else { stride }));
+    result
+}
+
+impl fmt::Display for Tensor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let values: Vec<String> = self.data.iter().map(|x| x.value().to_string()).collect();
+        write!(f, "Tensor{:?}[{}]", self.shape, values.join(", "))
+    }
+}
+
+macro_rules! elementwise {
+    ($trait:ident, $method:ident, $op:tt) => {
+        impl ops::$trait<&Tensor> for &Tensor {
+            type Output = Tensor;
+
+            fn $method(self, rhs: &Tensor) -> Tensor {
+                self.zip_with(rhs, |a, b| a $op b)
+            }
+        }
+
+        impl ops::$trait<Tensor> for Tensor {
+            type Output = Tensor;
+
+            fn $method(self, rhs: Tensor) -> Tensor {
+                &self $op &rhs
+            }
+        }
+
+        impl ops::$trait<f64> for &Tensor {
+            type Output = Tensor;
+
+            fn $method(self, rhs: f64) -> Tensor {
+                self.map(|a| a $op rhs)
+            }
+        }
+
+        impl ops::$trait<f64> for Tensor {
+            type Output = Tensor;
+
+            fn $method(self, rhs: f64) -> Tensor {
+                &self $op rhs
+            }
+        }
+    };
+}
+
+elementwise!(Add, add, +);
+elementwise!(Sub, sub, -);
+elementwise!(Mul, mul, *);
+elementwise!(Div, div, /);
+
+impl ops::Neg for &Tensor {
+    type Output = Tensor;
+
+    fn neg(self) -> Tensor {
+        self.map(|a| -a)
+    }
+}
+
+impl ops::Neg for Tensor {
+    type Output = Tensor;
+
+    fn neg(self) -> Tensor {
+        -&self
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shapes_and_strides() {
+        let t = Tensor::new(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3]);
+        assert_eq!(t.strides(), &[3, 1]);
+        assert_eq!(t.get(&[1, 0]).value(), 4.0);
+        let tt = t.transpose();
+        assert_eq!(tt.shape(), &[3, 2]);
+        assert_eq!(tt.to_vec(), vec![1.0, 4.0, 2.0, 5.0, 3.0, 6.0]);
+        assert!(tt.get(&[2, 1]).ptr_eq(&t.get(&[1, 2])));
+        assert_eq!(t.reshape(&[3, 2]).unwrap().get(&[2, 1]).value(), 6.0);
+        let error = t.reshape(&[4]).unwrap_err();
+        assert!(matches!(error, BabygradError::Elements { expected: 4, found: 6 }));
+        assert_eq!(error.to_string(), "expected 4 values, found 6");
+        assert_eq!(Tensor::scalar(2.0).shape(), &[] as &[usize]);
+        assert_eq!(Tensor::rand(&[2, 2, 2]).strides(), &[4, 2, 1]);
+    }
+
+    #[test]
+    fn broadcasting() {
+        let m = Tensor::new(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3]);
+        let row = Tensor::new(&[10.0, 20.0, 30.0], &[3]);
+        let column = Tensor::new(&[100.0, 200.0], &[2, 1]);
+        assert_eq!((&m + &row).to_vec(), vec![11.0, 22.0, 33.0, 14.0, 25.0, 36.0]);
+        assert_eq!((&m * &column).to_vec(), vec![100.0, 200.0, 300.0, 800.0, 1000.0, 1200.0]);
+        let outer = &row - &column;
+        assert_eq!(outer.shape(), &[2, 3]);
+        assert_eq!(outer.get(&[1, 2]).value(), -170.0);
+        assert_eq!((-(&m / 2.0)).get(&[0, 1]).value(), -1.0);
+
+        let error = m.try_zip_with(&Tensor::zeros(&[2]), |a, b| a + b).unwrap_err();
+        assert!(matches!(error, BabygradError::Broadcast { .. }));
+        assert_eq!(error.to_string(), "incompatible shapes [2, 3] and [2]");
+    }
+
+    #[test]
+    fn matmul() {
+        let a = Tensor::new(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3]);
+        let b = Tensor::new(&[1.0, 0.0, -1.0, 2.0, 0.5, 1.0], &[3, 2]);
+        let c = a.matmul(&b);
+        assert_eq!(c.shape(), &[2, 2]);
+        assert_eq!(c.to_vec(), vec![0.5, 7.0, 2.0, 16.0]);
+
+        let v = Tensor::new(&[1.0, 1.0, 1.0], &[3]);
+        assert_eq!(a.matmul(&v).to_vec(), vec![6.0, 15.0]);
+        assert_eq!(v.matmul(&b).shape(), &[2]);
+        assert_eq!(v.matmul(&v).shape(), &[] as &[usize]);
+        assert!(a.try_matmul(&a).is_err());
+    }
+
+    #[test]
+    fn reductions_and_backward() {
+        let a = Tensor::new(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3]);
+        assert_eq!(a.sum_axis(0).to_vec(), vec![5.0, 7.0, 9.0]);
+        assert_eq!(a.mean_axis(1).to_vec(), vec![2.0, 5.0]);
+        assert_eq!(a.sum().to_vec(), vec![21.0]);
+
+        // d/da mean(a^2) = 2a / 6
+        let loss = a.pow(2.0).mean();
+        loss.backward();
+        for (x, gradient) in a.to_vec().iter().zip(a.gradients()) {
+            assert_approx!(gradient, x / 3.0);
+        }
+
+        // the gradient of a broadcast operand is summed over the repeats
+        let b = Tensor::new(&[1.0, -1.0, 0.5], &[3]);
+        (&a * &b).sum().backward();
+        assert_eq!(b.gradients(), vec![5.0, 7.0, 9.0]);
+        assert!(a.try_backward().is_err());
+    }
+
+    #[test]
+    fn empty_and_out_of_range_reductions() {
+        let empty = Tensor::zeros(&[2, 0]);
+        assert_eq!(empty.mean().to_vec(), vec![0.0]);
+        assert_eq!(empty.mean_axis(1).to_vec(), vec![0.0, 0.0]);
+        assert!(empty.mean_axis(0).is_empty());
+
+        let a = Tensor::new(&[1.0, 2.0, 3.0], &[3]);
+        let error = a.try_sum_axis(1).unwrap_err();
+        assert!(matches!(error, BabygradError::Axis { axis: 1, ndim: 1 }));
+        assert_eq!(error.to_string(), "axis 1 out of range for 1 dimensions");
+        assert!(matches!(a.try_mean_axis(3), Err(BabygradError::Axis { axis: 3, ndim: 1 })));
+        assert!(matches!(a.try_backward(), Err(BabygradError::Elements { expected: 1, found: 3 })));
+        assert!(std::panic::catch_unwind(|| a.mean_axis(1)).is_err());
+    }
+}
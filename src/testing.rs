@@ -0,0 +1,21 @@
+//! Helpers shared by the unit tests.
+
+use crate::{gradcheck, GradcheckOptions, Op, Value};
+
+macro_rules! assert_approx {
+    ($a:expr , $b:expr) => {
+        assert!(($a - $b).abs() < 1e-8, "{} !~= {}", $a, $b);
+    };
+}
+
+/// A labelled input leaf.
+pub fn leaf(value: f64, label: &str) -> Value {
+    Value::from_with_label(value, vec![], Op::None, Some(label.to_owned()))
+}
+
+/// Checks the gradients of `f` at `inputs` with `gradcheck`, `name` tells
+/// the failing case apart.
+pub fn assert_gradcheck(name: &str, f: impl Fn(&[Value]) -> Value, inputs: &[f64]) {
+    let report = gradcheck(f, &Value::vec(inputs), &GradcheckOptions::default());
+    assert!(report.passed(), "{}{:?}: {}", name, inputs, report);
+}